@@ -0,0 +1,52 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use WahWah::fir::{bandpass_fir, MinimumPhase, Window};
+use WahWah::simd::{dot_product_scalar, select_dot_product, DotProduct};
+
+const BLOCK_SIZE: usize = 512;
+
+/// Direct-form convolution of one block, the way the FIR filter slides its kernel over the input history
+fn convolve_block(dot_product: DotProduct, history: &[f32], taps: &[f32], output: &mut [f32]) {
+    for (i, sample) in output.iter_mut().enumerate() {
+        *sample = dot_product(&history[i..i + taps.len()], taps);
+    }
+}
+
+fn fir_inner_loop(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fir_inner_loop");
+    // Picked once, like the filter does, so only the dot products are timed
+    let dot_product_simd = select_dot_product();
+    for num_taps in [101, 255, 511] {
+        let taps: Vec<f32> = (0..num_taps).map(|i| f32::sin(i as f32 * 0.1) / num_taps as f32).collect();
+        let history: Vec<f32> = (0..BLOCK_SIZE + num_taps).map(|i| f32::sin(i as f32 * 0.37)).collect();
+        let mut output = vec![0.0; BLOCK_SIZE];
+
+        group.bench_with_input(BenchmarkId::new("scalar", num_taps), &num_taps, |b, _| {
+            b.iter(|| convolve_block(dot_product_scalar, black_box(&history), black_box(&taps), &mut output))
+        });
+        group.bench_with_input(BenchmarkId::new("simd", num_taps), &num_taps, |b, _| {
+            b.iter(|| convolve_block(dot_product_simd, black_box(&history), black_box(&taps), &mut output))
+        });
+    }
+    group.finish();
+}
+
+/// One Minimum Phase kernel update, designing a kernel and converting it, which runs every 128 samples of every
+/// channel while the band moves. At 8x oversampling those 128 samples last 2.9 ms at 44.1 kHz
+fn minimum_phase_update(c: &mut Criterion) {
+    let mut group = c.benchmark_group("minimum_phase_update");
+    let mut converter = MinimumPhase::new(4095);
+    for num_taps in [255, 1023, 4095] {
+        let mut taps = vec![0.0; num_taps];
+        group.bench_with_input(BenchmarkId::from_parameter(num_taps), &num_taps, |b, _| {
+            b.iter(|| {
+                bandpass_fir(&mut taps, black_box(500.0), black_box(1500.0), 352800.0, Window::Hann);
+                converter.convert(&mut taps);
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, fir_inner_loop, minimum_phase_update);
+criterion_main!(benches);
@@ -0,0 +1,204 @@
+use std::sync::Arc;
+
+use realfft::num_complex::Complex;
+use realfft::{ComplexToReal, RealFftPlanner, RealToComplex};
+
+/// Uniformly partitioned FFT convolution of the tail of a kernel
+///
+/// The caller runs the first ```partition_size``` taps of a kernel in direct form and hands everything after them to this
+/// engine, which is what keeps it free of latency: the tail is delayed by at least one partition, so its contribution to
+/// the next ```partition_size``` output samples is known as soon as the current partition of input is complete. That
+/// contribution is computed with overlap-save in the frequency domain and then read back one sample at a time through
+/// ```tail```.
+///
+/// Two kernels are kept so the caller can crossfade from the previous kernel to the current one, and both may have any
+/// length up to the maximum the engine was created for.
+pub struct PartitionedConvolver {
+    partition_size: usize,
+    max_partitions: usize,
+    num_partitions: usize,  // Partitions in use by the current kernel
+    previous_num_partitions: usize,
+    forward: Arc<dyn RealToComplex<f32>>,
+    inverse: Arc<dyn ComplexToReal<f32>>,
+    input_block: Vec<f32>,  // The last two partitions of input, oldest first
+    block_position: usize,  // How far the newest input partition has been filled
+    delay_line: Vec<Complex<f32>>,  // Spectra of the most recent input partitions, max_partitions x (partition_size + 1)
+    delay_line_index: usize,  // Partition slot holding the newest spectrum
+    spectra: Vec<Complex<f32>>,  // Spectra of the current kernel's partitions, same layout as delay_line
+    previous_spectra: Vec<Complex<f32>>,
+    tail: Vec<f32>,  // Contribution of the current kernel to the running output partition
+    previous_tail: Vec<f32>,
+    time_scratch: Vec<f32>,
+    freq_scratch: Vec<Complex<f32>>,
+    fft_scratch: Vec<Complex<f32>>,
+}
+
+impl PartitionedConvolver {
+    /// Creates a silent convolver for kernel tails of up to ```max_tail_taps``` taps
+    pub fn new(max_tail_taps: usize, partition_size: usize) -> Self {
+        let fft_size = 2 * partition_size;
+        let num_bins = partition_size + 1;
+        let max_partitions = max_tail_taps.div_ceil(partition_size).max(1);
+
+        let mut planner = RealFftPlanner::<f32>::new();
+        let forward = planner.plan_fft_forward(fft_size);
+        let inverse = planner.plan_fft_inverse(fft_size);
+        let scratch_len = forward.get_scratch_len().max(inverse.get_scratch_len());
+
+        PartitionedConvolver {
+            partition_size,
+            max_partitions,
+            num_partitions: 0,
+            previous_num_partitions: 0,
+            forward,
+            inverse,
+            input_block: vec![0.0; fft_size],
+            block_position: 0,
+            delay_line: vec![Complex::new(0.0, 0.0); max_partitions * num_bins],
+            delay_line_index: 0,
+            spectra: vec![Complex::new(0.0, 0.0); max_partitions * num_bins],
+            previous_spectra: vec![Complex::new(0.0, 0.0); max_partitions * num_bins],
+            tail: vec![0.0; partition_size],
+            previous_tail: vec![0.0; partition_size],
+            time_scratch: vec![0.0; fft_size],
+            freq_scratch: vec![Complex::new(0.0, 0.0); num_bins],
+            fft_scratch: vec![Complex::new(0.0, 0.0); scratch_len],
+        }
+    }
+
+    /// Clears the input history
+    pub fn reset(&mut self) {
+        self.input_block.fill(0.0);
+        self.block_position = 0;
+        self.delay_line.fill(Complex::new(0.0, 0.0));
+        self.delay_line_index = 0;
+        self.tail.fill(0.0);
+        self.previous_tail.fill(0.0);
+    }
+
+    /// Clears the input history and both kernels, so the convolver is silent again
+    pub fn clear_kernels(&mut self) {
+        self.reset();
+        self.num_partitions = 0;
+        self.previous_num_partitions = 0;
+        self.spectra.fill(Complex::new(0.0, 0.0));
+        self.previous_spectra.fill(Complex::new(0.0, 0.0));
+    }
+
+    /// Loads the tail of a new kernel, i.e. every tap after the ones run in direct form, in its original order
+    /// An empty tail is allowed for kernels that are run completely in direct form
+    /// The previous kernel becomes the blend of the previous and current kernels at ```crossfade_mix```,
+    /// where 1.0 means the current kernel is fully faded in
+    pub fn set_kernel(&mut self, tail_kernel: &[f64], crossfade_mix: f32) {
+        let num_bins = self.partition_size + 1;
+        // Either spectrum buffer may hold this many partitions, everything after them is zero
+        let stale_partitions = self.num_partitions.max(self.previous_num_partitions);
+        if crossfade_mix >= 1.0 {
+            std::mem::swap(&mut self.previous_spectra, &mut self.spectra);
+            std::mem::swap(&mut self.previous_tail, &mut self.tail);
+            self.previous_num_partitions = self.num_partitions;
+        } else {
+            // Partitions past a kernel's length are kept at zero, so blending kernels of different lengths just works
+            let blended_partitions = self.previous_num_partitions.max(self.num_partitions);
+            let blended_bins = blended_partitions * num_bins;
+            for (previous, current) in self.previous_spectra[..blended_bins].iter_mut().zip(self.spectra.iter()) {
+                *previous += (*current - *previous) * crossfade_mix;
+            }
+            for (previous, current) in self.previous_tail.iter_mut().zip(self.tail.iter()) {
+                *previous += (*current - *previous) * crossfade_mix;
+            }
+            self.previous_num_partitions = blended_partitions;
+        }
+
+        self.num_partitions = tail_kernel.len().div_ceil(self.partition_size);
+        assert!(self.num_partitions <= self.max_partitions);
+
+        // The inverse transform is not normalised, so fold its scaling into the kernel
+        let scale = 1.0 / (2 * self.partition_size) as f32;
+        for partition in 0..self.num_partitions {
+            self.time_scratch.fill(0.0);
+            let taps = tail_kernel.iter().skip(partition * self.partition_size).take(self.partition_size);
+            for (sample, &coefficient) in self.time_scratch.iter_mut().zip(taps) {
+                *sample = coefficient as f32 * scale;
+            }
+            let spectrum = &mut self.spectra[partition * num_bins..(partition + 1) * num_bins];
+            self.forward
+                .process_with_scratch(&mut self.time_scratch, spectrum, &mut self.fft_scratch)
+                .expect("buffer sizes match the FFT plan");
+        }
+        if stale_partitions > self.num_partitions {
+            self.spectra[self.num_partitions * num_bins..stale_partitions * num_bins].fill(Complex::new(0.0, 0.0));
+        }
+
+        // The running output partition was computed with the old kernel, redo it from the same input history
+        self.compute_tail(false);
+    }
+
+    /// Return the tail contribution of the current and the previous kernel to the output sample about to be produced
+    pub fn tail(&self) -> (f32, f32) {
+        (self.tail[self.block_position], self.previous_tail[self.block_position])
+    }
+
+    /// Feeds one input sample, call this after reading ```tail``` for that sample
+    /// The previous kernel's tail is only kept up to date while ```crossfading``` is set
+    pub fn push_sample(&mut self, sample: f32, crossfading: bool) {
+        self.input_block[self.partition_size + self.block_position] = sample;
+        self.block_position += 1;
+        if self.block_position < self.partition_size {
+            return;
+        }
+        self.block_position = 0;
+
+        // Transform the last two input partitions into the newest slot of the delay line (overlap-save)
+        // This always runs, so the history is complete whenever a longer kernel is loaded
+        let num_bins = self.partition_size + 1;
+        self.delay_line_index = (self.delay_line_index + self.max_partitions - 1) % self.max_partitions;
+        self.time_scratch.copy_from_slice(&self.input_block);
+        let slot = &mut self.delay_line[self.delay_line_index * num_bins..(self.delay_line_index + 1) * num_bins];
+        self.forward
+            .process_with_scratch(&mut self.time_scratch, slot, &mut self.fft_scratch)
+            .expect("buffer sizes match the FFT plan");
+        self.input_block.copy_within(self.partition_size.., 0);
+
+        self.compute_tail(false);
+        if crossfading {
+            self.compute_tail(true);
+        }
+    }
+
+    /// Multiplies every input partition with the kernel partition it lines up with, sums them and transforms back
+    /// into the tail of either the current or the ```previous``` kernel
+    fn compute_tail(&mut self, previous: bool) {
+        let (spectra, num_partitions, tail) = if previous {
+            (&self.previous_spectra, self.previous_num_partitions, &mut self.previous_tail)
+        } else {
+            (&self.spectra, self.num_partitions, &mut self.tail)
+        };
+        if num_partitions == 0 {
+            tail.fill(0.0);
+            return;
+        }
+
+        let num_bins = self.partition_size + 1;
+        let accumulator = &mut self.freq_scratch;
+        accumulator.fill(Complex::new(0.0, 0.0));
+        for partition in 0..num_partitions {
+            // Tail partition p is applied to the input from p + 1 partitions ago, the newest slot holds the input
+            // from one partition ago by the time the next output partition is produced
+            let slot = (self.delay_line_index + partition) % self.max_partitions;
+            let input = &self.delay_line[slot * num_bins..(slot + 1) * num_bins];
+            let kernel = &spectra[partition * num_bins..(partition + 1) * num_bins];
+            for ((acc, x), h) in accumulator.iter_mut().zip(input.iter()).zip(kernel.iter()) {
+                *acc += x * h;
+            }
+        }
+        accumulator[0].im = 0.0;
+        accumulator[num_bins - 1].im = 0.0;
+        self.inverse
+            .process_with_scratch(accumulator, &mut self.time_scratch, &mut self.fft_scratch)
+            .expect("buffer sizes match the FFT plan");
+
+        // Overlap-save keeps the second half, the first half is circular wrap-around
+        tail.copy_from_slice(&self.time_scratch[self.partition_size..]);
+    }
+}
@@ -1,68 +1,213 @@
+/// How strongly the exponential and logarithmic curves bend, higher values bend them further from a straight line
+const CURVE_AMOUNT: f32 = 4.0;
+/// How long the input level takes to fall by 1/e once the input drops, long enough to bridge the zero crossings
+/// of the lowest notes and short enough to follow the end of a note
+const LEVEL_RELEASE_MS: f32 = 20.0;
+
 #[derive(Debug, PartialEq, Eq)]
 enum EnvelopeState {
     WAITING,
     ATTACK,
+    HOLD,
     DECAY,
-    FINAL,
+    SUSTAIN,
+    RELEASE,
+}
+
+/// Shape of a stage of the envelope on its way from one level to the next
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Curve {
+    Linear,
+    /// Changes by the same ratio each sample, so it rises slowly at first and falls quickly at first
+    Exponential,
+    /// The mirror image of ```Exponential```, rises quickly at first and falls slowly at first
+    Logarithmic,
 }
+
+impl Curve {
+    /// Return how far a stage has got towards its target, from 0.0 to 1.0, when it is ```position``` of the way
+    /// through its time
+    pub fn progress(&self, position: f32, rising: bool) -> f32 {
+        let bend = |x: f32| (f32::exp(CURVE_AMOUNT * x) - 1.0) / (f32::exp(CURVE_AMOUNT) - 1.0);
+        match (*self, rising) {
+            (Curve::Linear, _) => position,
+            (Curve::Exponential, true) | (Curve::Logarithmic, false) => bend(position),
+            (Curve::Exponential, false) | (Curve::Logarithmic, true) => 1.0 - bend(1.0 - position),
+        }
+    }
+}
+
 pub struct Envelope {
     state: EnvelopeState,
     curr_value: f32,
-    attack_rate: f32,
-    decay_rate: f32,
+    stage_start: f32,  // Value the current stage started from
+    stage_position: f32,  // How far through its time the current stage is, from 0.0 to 1.0
+    attack_ms: f32,
+    decay_ms: f32,
+    release_ms: f32,
+    attack_curve: Curve,
+    decay_curve: Curve,
+    release_curve: Curve,
+    sustain_level: f32,
     onset_threshold: f32,
     reset_threshold: f32,
+    hold_samples: usize,
+    hold_counter: usize,  // Samples left in the HOLD state
+    level: f32,  // Rectified input, jumps up to each peak and falls over LEVEL_RELEASE_MS
+    level_release_coeff: f32,
+    triggered: bool,
+    sample_rate: f32,
 }
 
-/// Attack-decay envelope
-/// Sits in the WAITING state until amplitude of the process function's input exceeds the onset_threshold,
-/// Then proceeds to run through the ATTACK and DECAY stages of envelope and returns to WAITING
+/// Attack-hold-decay-sustain-release envelope
+/// Sits in the WAITING state until the level of the process function's input exceeds the onset_threshold,
+/// Then proceeds to run through the ATTACK, HOLD and DECAY stages of envelope and stays at the sustain level
+/// until the level falls to the reset_threshold, which starts the RELEASE back to zero.
+/// The level follows the peaks of the input rather than each sample, so the zero crossings of a note don't end it.
+/// A sustained note can not trigger it again, a new note in the release can.
+/// Attack and hold always run to the end, so even the shortest pluck gets the whole sweep
 impl Envelope {
-    pub fn new(attack_rate: f32, decay_rate: f32, onset_threshold: f32, reset_threshold: f32) -> Self {
+    /// Creates an envelope with 20 ms attack, 50 ms decay, no sustain and 100 ms release
+    pub fn new(sample_rate: f32) -> Self {
         return Envelope {
             state: EnvelopeState::WAITING,
             curr_value: 0.0,
-            attack_rate: attack_rate,
-            decay_rate: decay_rate,
-            onset_threshold: onset_threshold,
-            reset_threshold: reset_threshold,
+            stage_start: 0.0,
+            stage_position: 0.0,
+            attack_ms: 20.0,
+            decay_ms: 50.0,
+            release_ms: 100.0,
+            attack_curve: Curve::Linear,
+            decay_curve: Curve::Linear,
+            release_curve: Curve::Linear,
+            sustain_level: 0.0,
+            onset_threshold: 0.15,
+            reset_threshold: 0.05,
+            hold_samples: 0,
+            hold_counter: 0,
+            level: 0.0,
+            level_release_coeff: f32::exp(-1.0 / (LEVEL_RELEASE_MS * 0.001 * sample_rate)),
+            triggered: false,
+            sample_rate,
         }
     }
 
+    /// Goes back to waiting for a note at zero, keeping the times, curves and thresholds
+    pub fn reset(&mut self) {
+        self.state = EnvelopeState::WAITING;
+        self.curr_value = 0.0;
+        self.stage_start = 0.0;
+        self.stage_position = 0.0;
+        self.hold_counter = 0;
+        self.level = 0.0;
+        self.triggered = false;
+    }
+
     /// Return next value of envelope given current sample
     pub fn process_one_sample(&mut self, sample: &f32) -> f32 {
-        if matches!(self.state, EnvelopeState::WAITING) {
-            if *sample > self.onset_threshold {
-                self.state = EnvelopeState::ATTACK;
-            }
+        self.follow_level(*sample);
+        let armed = matches!(self.state, EnvelopeState::WAITING | EnvelopeState::RELEASE);
+        self.process(armed && self.level > self.onset_threshold)
+    }
+
+    /// Return next value of the envelope given whether an external onset detector found an onset at this sample
+    /// An onset restarts the attack from the current value in any state but ATTACK, so a new note is never missed
+    /// because the last one is still decaying. ```sample``` still decides when the sustain ends
+    pub fn process_onset(&mut self, onset: bool, sample: &f32) -> f32 {
+        self.follow_level(*sample);
+        self.process(onset && self.state != EnvelopeState::ATTACK)
+    }
+
+    /// Return whether the last sample processed was an onset, moving the envelope to ATTACK
+    pub fn triggered(&self) -> bool {
+        self.triggered
+    }
+
+    /// Update the length of each stage in milliseconds, can be called each sample
+    pub fn set_times(&mut self, attack_ms: f32, hold_ms: f32, decay_ms: f32, release_ms: f32) {
+        self.attack_ms = attack_ms;
+        self.decay_ms = decay_ms;
+        self.release_ms = release_ms;
+        self.hold_samples = (hold_ms * 0.001 * self.sample_rate) as usize;
+        // A shorter hold takes effect in the middle of holding as well
+        self.hold_counter = self.hold_counter.min(self.hold_samples.max(1));
+    }
+
+    /// Update the shape of the attack, decay and release, can be called each sample
+    pub fn set_curves(&mut self, attack: Curve, decay: Curve, release: Curve) {
+        self.attack_curve = attack;
+        self.decay_curve = decay;
+        self.release_curve = release;
+    }
+
+    /// Update the level the envelope stays at while the note lasts, from 0.0 to 1.0
+    pub fn set_sustain(&mut self, sustain_level: f32) {
+        self.sustain_level = sustain_level;
+    }
+
+    /// Update the input levels that start a note and end it
+    pub fn set_thresholds(&mut self, onset_threshold: f32, reset_threshold: f32) {
+        self.onset_threshold = onset_threshold;
+        self.reset_threshold = reset_threshold;
+    }
+
+    fn follow_level(&mut self, sample: f32) {
+        self.level = sample.abs().max(self.level * self.level_release_coeff);
+    }
+
+    fn process(&mut self, trigger: bool) -> f32 {
+        self.triggered = trigger;
+        if trigger {
+            self.start_stage(EnvelopeState::ATTACK);
         }
         else if matches!(self.state, EnvelopeState::ATTACK) {
-            self.curr_value += self.attack_rate;
-            if self.curr_value >= 1.0 {
-                self.curr_value = 1.0;
-                self.state = EnvelopeState::DECAY;
+            if self.run_stage(self.attack_ms, self.attack_curve, 1.0) {
+                self.hold_counter = self.hold_samples;
+                if self.hold_samples > 0 {
+                    self.state = EnvelopeState::HOLD;
+                } else {
+                    self.start_stage(EnvelopeState::DECAY);
+                }
+            }
+        }
+        else if matches!(self.state, EnvelopeState::HOLD) {
+            self.hold_counter -= 1;
+            if self.hold_counter == 0 {
+                self.start_stage(EnvelopeState::DECAY);
             }
         }
         else if matches!(self.state, EnvelopeState::DECAY) {
-            self.curr_value -= self.decay_rate;
-            if self.curr_value <= 0.0 {
-                self.curr_value = 0.0;
-                self.state = EnvelopeState::WAITING;
+            if self.level <= self.reset_threshold {
+                self.start_stage(EnvelopeState::RELEASE);
+            } else if self.run_stage(self.decay_ms, self.decay_curve, self.sustain_level) {
+                self.state = EnvelopeState::SUSTAIN;
             }
-        } else if matches!(self.state, EnvelopeState::FINAL) {
-            if *sample <= self.reset_threshold {
-                self.state = EnvelopeState::WAITING;
+        }
+        else if matches!(self.state, EnvelopeState::SUSTAIN) {
+            self.curr_value = self.sustain_level;
+            if self.level <= self.reset_threshold {
+                self.start_stage(EnvelopeState::RELEASE);
             }
         }
+        else if matches!(self.state, EnvelopeState::RELEASE) && self.run_stage(self.release_ms, self.release_curve, 0.0) {
+            self.state = EnvelopeState::WAITING;
+        }
         return self.curr_value;
     }
 
-    /// Update all parameters of the envlope, can be called each sample
-    pub fn set_params(&mut self, attack_rate:f32, decay_rate: f32, onset_threshold: f32, reset_threshold: f32){
-        self.attack_rate = attack_rate;
-        self.decay_rate = decay_rate;
-        self.onset_threshold = onset_threshold;
-        self.reset_threshold = reset_threshold;
+    fn start_stage(&mut self, state: EnvelopeState) {
+        self.state = state;
+        self.stage_start = self.curr_value;
+        self.stage_position = 0.0;
+    }
+
+    /// Moves the current stage on by one sample towards ```target``` and return whether it has reached it
+    fn run_stage(&mut self, time_ms: f32, curve: Curve, target: f32) -> bool {
+        let samples = time_ms * 0.001 * self.sample_rate;
+        self.stage_position = if samples <= 1.0 { 1.0 } else { (self.stage_position + 1.0 / samples).min(1.0) };
+        let rising = target > self.stage_start;
+        self.curr_value = self.stage_start + (target - self.stage_start) * curve.progress(self.stage_position, rising);
+        self.stage_position >= 1.0
     }
 
 }
@@ -70,47 +215,238 @@ impl Envelope {
 mod tests {
     use super::*;
 
+    /// Envelope at 1 kHz, so times in milliseconds are times in samples
+    fn envelope(attack_ms: f32, hold_ms: f32, decay_ms: f32, sustain_level: f32, release_ms: f32) -> Envelope {
+        let mut env = Envelope::new(1000.0);
+        env.set_times(attack_ms, hold_ms, decay_ms, release_ms);
+        env.set_sustain(sustain_level);
+        env.set_thresholds(0.5, 0.2);
+        env
+    }
+
     #[test]
     fn test_new_envelope() {
-        let env = Envelope::new(0.1, 0.05, 0.5, 0.2);
+        let env = Envelope::new(44100.0);
         assert_eq!(env.state, EnvelopeState::WAITING);
         assert_eq!(env.curr_value, 0.0);
-        assert_eq!(env.attack_rate, 0.1);
-        assert_eq!(env.decay_rate, 0.05);
-        assert_eq!(env.onset_threshold, 0.5);
-        assert_eq!(env.reset_threshold, 0.2);
+        assert_eq!(env.attack_curve, Curve::Linear);
+        assert_eq!(env.sustain_level, 0.0);
+    }
+
+    #[test]
+    fn test_process_one_sample_waiting_to_attack() {
+        let mut env = envelope(10.0, 0.0, 10.0, 0.0, 10.0);
+        env.process_one_sample(&0.5);  // Has to exceed the onset_threshold
+        assert_eq!(env.state, EnvelopeState::WAITING);
+        env.process_one_sample(&0.6);
+        assert_eq!(env.state, EnvelopeState::ATTACK);
+    }
+
+    #[test]
+    fn test_attack_takes_attack_time() {
+        let mut env = envelope(4.0, 0.0, 10.0, 0.0, 10.0);
+        env.process_one_sample(&0.6);
+        for expected in [0.25, 0.5, 0.75] {
+            assert_eq!(env.process_one_sample(&0.6), expected);
+            assert_eq!(env.state, EnvelopeState::ATTACK);
+        }
+        assert_eq!(env.process_one_sample(&0.6), 1.0);  // Should cap at 1.0
+        assert_eq!(env.state, EnvelopeState::DECAY);
+    }
+
+    #[test]
+    fn test_times_are_independent_of_sample_rate() {
+        for sample_rate in [44100.0, 96000.0] {
+            let mut env = Envelope::new(sample_rate);
+            env.set_times(10.0, 0.0, 100.0, 100.0);
+            env.set_thresholds(0.5, 0.2);
+            env.process_one_sample(&0.6);
+            let samples = (0..sample_rate as usize).position(|_| env.process_one_sample(&0.6) >= 1.0).unwrap() + 1;
+            assert!(f32::abs(samples as f32 / sample_rate - 0.01) < 0.0001);
+        }
+    }
+
+    #[test]
+    fn test_attack_to_hold_to_decay() {
+        let mut env = envelope(1.0, 3.0, 20.0, 0.0, 10.0);
+        env.process_one_sample(&0.6);
+        env.process_one_sample(&0.6);
+        assert_eq!(env.state, EnvelopeState::HOLD);
+        // Stays at the peak for the hold time, whatever the input does
+        for _ in 0..2 {
+            assert_eq!(env.process_one_sample(&0.0), 1.0);
+            assert_eq!(env.state, EnvelopeState::HOLD);
+        }
+        assert_eq!(env.process_one_sample(&0.6), 1.0);
+        assert_eq!(env.state, EnvelopeState::DECAY);
+        assert!(f32::abs(env.process_one_sample(&0.6) - 0.95) < 0.00001);
     }
 
     #[test]
-    fn test_process_one_sample_attack_to_decay() {
-        let mut env = Envelope::new(0.05, 0.05, 0.5, 0.2);
-        env.state = EnvelopeState::ATTACK;
-        env.curr_value = 0.95;
-        let sample = 0.6;  // Still above threshold, but should now switch to decay
-        let output = env.process_one_sample(&sample);
+    fn test_decay_to_sustain_to_release() {
+        let mut env = envelope(1.0, 0.0, 4.0, 0.6, 2.0);
+        env.process_one_sample(&0.6);
+        env.process_one_sample(&0.6);
+        for expected in [0.9, 0.8, 0.7] {
+            assert!(f32::abs(env.process_one_sample(&0.6) - expected) < 0.00001);
+        }
+        assert_eq!(env.process_one_sample(&0.6), 0.6);
+        assert_eq!(env.state, EnvelopeState::SUSTAIN);
+        // Held for as long as the note rings above the reset_threshold
+        for _ in 0..100 {
+            assert_eq!(env.process_one_sample(&0.3), 0.6);
+            assert!(!env.triggered());
+        }
+        // Silence ends the note once the level has fallen from 0.3 to the reset_threshold, after 20 * ln(1.5) samples
+        for _ in 0..8 {
+            assert_eq!(env.process_one_sample(&0.0), 0.6);
+        }
+        env.process_one_sample(&0.0);
+        assert_eq!(env.state, EnvelopeState::RELEASE);
+        assert_eq!(env.process_one_sample(&0.0), 0.3);
+        assert_eq!(env.process_one_sample(&0.0), 0.0);
+        assert_eq!(env.state, EnvelopeState::WAITING);
+    }
+
+    #[test]
+    fn test_decay_releases_when_note_ends() {
+        let mut env = envelope(1.0, 0.0, 100.0, 0.5, 10.0);
+        env.process_one_sample(&0.6);
+        env.process_one_sample(&0.6);
+        env.process_one_sample(&0.6);
         assert_eq!(env.state, EnvelopeState::DECAY);
-        assert_eq!(output, 1.0);  // Should cap at 1.0
+        let samples = (0..100).position(|_| {
+            env.process_one_sample(&0.1);
+            env.state == EnvelopeState::RELEASE
+        }).unwrap();
+        // The level falls from 0.6 to the reset_threshold in 20 * ln(3) samples, long before the decay ends
+        assert_eq!(samples, 21);
+    }
+
+    #[test]
+    fn test_sustained_note_triggers_once() {
+        let mut env = envelope(2.0, 2.0, 2.0, 0.0, 2.0);
+        let triggers = (0..1000).filter(|_| {
+            env.process_one_sample(&0.8);
+            env.triggered()
+        }).count();
+        assert_eq!(triggers, 1);
+        assert_eq!(env.state, EnvelopeState::SUSTAIN);
     }
 
     #[test]
-    fn test_process_one_sample_decay_to_waiting() {
-        let mut env = Envelope::new(0.1, 0.1, 0.5, 0.2);
-        env.state = EnvelopeState::DECAY;
-        env.curr_value = 0.1;
-        let sample = 0.6;  // Still above the reset_threshold, should not affect state
-        let output = env.process_one_sample(&sample);
+    fn test_sine_triggers_once() {
+        // A note held for a second, which crosses zero 440 times
+        let sample_rate = 44100.0;
+        let mut env = Envelope::new(sample_rate);
+        env.set_sustain(0.5);
+        let triggers = (0..sample_rate as usize).filter(|i| {
+            let sample = 0.5 * f32::sin(2.0 * std::f32::consts::PI * 220.0 * *i as f32 / sample_rate);
+            env.process_one_sample(&sample);
+            env.triggered()
+        }).count();
+        assert_eq!(triggers, 1);
+        assert_eq!(env.state, EnvelopeState::SUSTAIN);
+        for _ in 0..sample_rate as usize {
+            env.process_one_sample(&0.0);
+        }
         assert_eq!(env.state, EnvelopeState::WAITING);
-        assert_eq!(output, 0.0);  // Should decay to zero and return to waiting
     }
 
     #[test]
-    fn test_set_params() {
-        let mut env = Envelope::new(0.1, 0.05, 0.5, 0.2);
-        env.set_params(0.2, 0.1, 0.6, 0.3);
-        assert_eq!(env.attack_rate, 0.2);
-        assert_eq!(env.decay_rate, 0.1);
-        assert_eq!(env.onset_threshold, 0.6);
-        assert_eq!(env.reset_threshold, 0.3);
+    fn test_sine_sustain_to_release_to_retrigger() {
+        let sample_rate = 44100.0;
+        let mut env = Envelope::new(sample_rate);
+        env.set_times(5.0, 0.0, 5.0, 200.0);
+        env.set_sustain(0.5);
+        let sine = |i: usize| 0.5 * f32::sin(2.0 * std::f32::consts::PI * 220.0 * i as f32 / sample_rate);
+        // The zero crossings of a held note don't release the sustain
+        for i in 0..4410 {
+            env.process_one_sample(&sine(i));
+        }
+        assert_eq!(env.state, EnvelopeState::SUSTAIN);
+        for i in 4410..22050 {
+            assert_eq!(env.process_one_sample(&sine(i)), 0.5);
+        }
+        // Once the note stops, the release starts within the time the level takes to fall below the reset_threshold
+        let samples = (0..4410).position(|_| {
+            env.process_one_sample(&0.0);
+            env.state == EnvelopeState::RELEASE
+        }).unwrap();
+        assert!(samples as f32 <= LEVEL_RELEASE_MS * 0.001 * sample_rate * f32::ln(0.5 / 0.05));
+        // A new note in the release triggers once and comes back to the sustain
+        for _ in 0..441 {
+            env.process_one_sample(&0.0);
+        }
+        let triggers = (0..4410).filter(|i| {
+            env.process_one_sample(&sine(*i));
+            env.triggered()
+        }).count();
+        assert_eq!(triggers, 1);
+        assert_eq!(env.state, EnvelopeState::SUSTAIN);
+    }
+
+    #[test]
+    fn test_new_note_retriggers_release() {
+        let mut env = envelope(1.0, 0.0, 1.0, 0.8, 10.0);
+        for _ in 0..4 {
+            env.process_one_sample(&0.6);
+        }
+        while env.state != EnvelopeState::RELEASE {
+            env.process_one_sample(&0.0);
+        }
+        // Between the thresholds is not a new note
+        env.process_one_sample(&0.4);
+        assert!(!env.triggered());
+        let value = env.process_one_sample(&0.6);
+        assert!(env.triggered());
+        assert!(value > 0.0 && value < 0.8);
+        assert_eq!(env.state, EnvelopeState::ATTACK);
+    }
+
+    #[test]
+    fn test_process_onset_retriggers_while_decaying() {
+        let mut env = envelope(2.0, 0.0, 4.0, 0.0, 10.0);
+        assert_eq!(env.process_onset(false, &0.0), 0.0);
+        assert!(!env.triggered());
+        env.process_onset(true, &0.0);
+        assert!(env.triggered());
+        // A second onset in the attack is the same note
+        env.process_onset(true, &0.6);
+        assert!(!env.triggered());
+        env.process_onset(false, &0.6);
+        env.process_onset(false, &0.6);
+        assert_eq!(env.state, EnvelopeState::DECAY);
+        // A new note restarts the attack from where the decay has got to
+        let value = env.process_onset(true, &0.6);
+        assert!(env.triggered());
+        assert_eq!(value, 0.75);
+        assert_eq!(env.process_onset(false, &0.6), 0.875);
+    }
+
+    #[test]
+    fn test_curves() {
+        for curve in [Curve::Linear, Curve::Exponential, Curve::Logarithmic] {
+            for rising in [true, false] {
+                assert_eq!(curve.progress(0.0, rising), 0.0);
+                assert!(f32::abs(curve.progress(1.0, rising) - 1.0) < 0.00001);
+            }
+        }
+        // Exponential rises slowly and falls quickly at first, logarithmic the other way around
+        assert!(Curve::Exponential.progress(0.5, true) < 0.5);
+        assert!(Curve::Exponential.progress(0.5, false) > 0.5);
+        assert!(Curve::Logarithmic.progress(0.5, true) > 0.5);
+        assert!(Curve::Logarithmic.progress(0.5, false) < 0.5);
+    }
+
+    #[test]
+    fn test_set_times() {
+        let mut env = Envelope::new(1000.0);
+        env.set_times(10.0, 100.0, 20.0, 30.0);
+        assert_eq!(env.attack_ms, 10.0);
+        assert_eq!(env.decay_ms, 20.0);
+        assert_eq!(env.release_ms, 30.0);
+        assert_eq!(env.hold_samples, 100);
     }
 
 }
@@ -0,0 +1,154 @@
+/// Length of the window the RMS detector averages over, in seconds
+const RMS_WINDOW_TIME: f32 = 0.01;
+
+/// Selects how the envelope follower measures the level of its input
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Detector {
+    /// Absolute value of each sample, follows every pick attack
+    Peak,
+    /// Root mean square over the last ```RMS_WINDOW_TIME``` seconds, follows the loudness more than the transients
+    Rms,
+}
+
+/// Envelope follower for touch-wah
+///
+/// Rectifies the input and smooths it with separate attack and release times, so the output rises as fast as the
+/// playing gets louder and falls back slowly as notes ring out. The times are in milliseconds, so the follower
+/// sounds the same at any sample rate.
+pub struct EnvelopeFollower {
+    detector: Detector,
+    attack_coeff: f32,
+    release_coeff: f32,
+    sensitivity: f32,  // Linear gain applied before the detector
+    gain: f32,  // Linear gain applied to the smoothed level
+    value: f32,
+    window: Vec<f32>,  // Squares of the last input samples, for the RMS detector
+    window_index: usize,
+    window_sum: f64,  // Running sum of ```window```
+    sample_rate: f32,
+}
+
+impl EnvelopeFollower {
+    /// Creates a peak follower with 10 ms attack and 100 ms release
+    pub fn new(sample_rate: f32) -> Self {
+        let mut follower = EnvelopeFollower {
+            detector: Detector::Peak,
+            attack_coeff: 0.0,
+            release_coeff: 0.0,
+            sensitivity: 1.0,
+            gain: 1.0,
+            value: 0.0,
+            window: vec![0.0; ((RMS_WINDOW_TIME * sample_rate) as usize).max(1)],
+            window_index: 0,
+            window_sum: 0.0,
+            sample_rate,
+        };
+        follower.set_params(Detector::Peak, 10.0, 100.0, 1.0, 1.0);
+        follower
+    }
+
+    /// Clears the level and the RMS window
+    pub fn reset(&mut self) {
+        self.value = 0.0;
+        self.window.fill(0.0);
+        self.window_index = 0;
+        self.window_sum = 0.0;
+    }
+
+    /// Update all parameters of the follower, can be called each sample
+    /// ```sensitivity``` and ```gain``` are linear gains before and after the smoothing
+    pub fn set_params(&mut self, detector: Detector, attack_ms: f32, release_ms: f32, sensitivity: f32, gain: f32) {
+        self.detector = detector;
+        self.attack_coeff = self.time_to_coeff(attack_ms);
+        self.release_coeff = self.time_to_coeff(release_ms);
+        self.sensitivity = sensitivity;
+        self.gain = gain;
+    }
+
+    /// Return next value of the envelope given current sample, between 0.0 and 1.0
+    pub fn process_one_sample(&mut self, sample: &f32) -> f32 {
+        let input = *sample * self.sensitivity;
+        // The window is kept up to date in both modes, so switching detectors does not start from stale values
+        let square = input * input;
+        self.window_sum += (square - self.window[self.window_index]) as f64;
+        self.window[self.window_index] = square;
+        self.window_index = (self.window_index + 1) % self.window.len();
+
+        let level = match self.detector {
+            Detector::Peak => input.abs(),
+            Detector::Rms => f32::sqrt((self.window_sum.max(0.0) / self.window.len() as f64) as f32),
+        };
+        let coeff = if level > self.value { self.attack_coeff } else { self.release_coeff };
+        self.value += coeff * (level - self.value);
+        (self.value * self.gain).min(1.0)
+    }
+
+    /// One-pole coefficient that covers 1 - 1/e of a step in ```time_ms```
+    fn time_to_coeff(&self, time_ms: f32) -> f32 {
+        let samples = time_ms * 0.001 * self.sample_rate;
+        if samples <= 1.0 {
+            1.0
+        } else {
+            1.0 - f32::exp(-1.0 / samples)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Number of samples it takes the follower to reach 1 - 1/e of a full-scale step
+    fn rise_time(sample_rate: f32, detector: Detector) -> usize {
+        let mut follower = EnvelopeFollower::new(sample_rate);
+        follower.set_params(detector, 5.0, 50.0, 1.0, 1.0);
+        (0..sample_rate as usize)
+            .position(|_| follower.process_one_sample(&1.0) >= 1.0 - f32::exp(-1.0))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_attack_time_is_independent_of_sample_rate() {
+        let at_44100 = rise_time(44100.0, Detector::Peak) as f32 / 44100.0;
+        let at_96000 = rise_time(96000.0, Detector::Peak) as f32 / 96000.0;
+        assert!(f32::abs(at_44100 - 0.005) < 0.0001);
+        assert!(f32::abs(at_96000 - 0.005) < 0.0001);
+    }
+
+    #[test]
+    fn test_release_is_slower_than_attack() {
+        let mut follower = EnvelopeFollower::new(44100.0);
+        follower.set_params(Detector::Peak, 1.0, 100.0, 1.0, 1.0);
+        for _ in 0..4410 {
+            follower.process_one_sample(&0.5);
+        }
+        assert!(f32::abs(follower.process_one_sample(&0.5) - 0.5) < 0.001);
+        // 10 ms into the release most of the level is still there
+        let mut value = 0.0;
+        for _ in 0..441 {
+            value = follower.process_one_sample(&0.0);
+        }
+        assert!(value > 0.4);
+    }
+
+    #[test]
+    fn test_rms_of_sine() {
+        let mut follower = EnvelopeFollower::new(44100.0);
+        follower.set_params(Detector::Rms, 1.0, 1.0, 1.0, 1.0);
+        let mut value = 0.0;
+        for i in 0..44100 {
+            value = follower.process_one_sample(&f32::sin(i as f32 * 2.0 * std::f32::consts::PI * 1000.0 / 44100.0));
+        }
+        assert!(f32::abs(value - f32::sqrt(0.5)) < 0.01);
+    }
+
+    #[test]
+    fn test_sensitivity_and_gain() {
+        let mut follower = EnvelopeFollower::new(44100.0);
+        follower.set_params(Detector::Peak, 0.0, 0.0, 2.0, 0.5);
+        assert_eq!(follower.process_one_sample(&0.25), 0.25);
+        // The output never goes past full depth
+        follower.set_params(Detector::Peak, 0.0, 0.0, 4.0, 2.0);
+        assert_eq!(follower.process_one_sample(&0.5), 1.0);
+    }
+}
@@ -0,0 +1,103 @@
+/// Position of an expression pedal, set by hand or by MIDI CC
+///
+/// Incoming CC values are calibrated to the range the pedal really sends, so a pedal that only reaches part of the
+/// CC range still sweeps from heel to toe, and a ```max``` below ```min``` turns the pedal around. The position
+/// glides to each new value over the smoothing time, which hides the steps of the 7-bit CC values.
+pub struct ExpressionPedal {
+    position: f32,  // Position the pedal glides towards, from 0.0 at the heel to 1.0 at the toe
+    value: f32,
+    smoothing_coeff: f32,
+    min: f32,  // CC value of the heel, from 0.0 to 1.0
+    max: f32,  // CC value of the toe, from 0.0 to 1.0
+    sample_rate: f32,
+}
+
+impl ExpressionPedal {
+    /// Creates a pedal in the middle of its travel, with 20 ms of smoothing and the full CC range
+    pub fn new(sample_rate: f32) -> Self {
+        let mut pedal = ExpressionPedal {
+            position: 0.5,
+            value: 0.5,
+            smoothing_coeff: 1.0,
+            min: 0.0,
+            max: 1.0,
+            sample_rate,
+        };
+        pedal.set_params(20.0, 0.0, 1.0);
+        pedal
+    }
+
+    /// Jumps to the current position without gliding
+    pub fn reset(&mut self) {
+        self.value = self.position;
+    }
+
+    /// Update the smoothing time in milliseconds and the CC values of the heel and the toe, can be called each sample
+    pub fn set_params(&mut self, smoothing_ms: f32, min: f32, max: f32) {
+        let samples = smoothing_ms * 0.001 * self.sample_rate;
+        self.smoothing_coeff = if samples <= 1.0 { 1.0 } else { 1.0 - f32::exp(-1.0 / samples) };
+        self.min = min;
+        self.max = max;
+    }
+
+    /// Moves the pedal to ```position```, from 0.0 at the heel to 1.0 at the toe
+    pub fn set_position(&mut self, position: f32) {
+        self.position = position.clamp(0.0, 1.0);
+    }
+
+    /// Moves the pedal to where a CC ```value``` from 0.0 to 1.0 puts it after calibration
+    pub fn set_cc_value(&mut self, value: f32) {
+        let range = self.max - self.min;
+        self.position = if range.abs() < f32::EPSILON {
+            // A pedal calibrated to a single value is a switch
+            if value >= self.min { 1.0 } else { 0.0 }
+        } else {
+            ((value - self.min) / range).clamp(0.0, 1.0)
+        };
+    }
+
+    /// Return the smoothed position of the pedal for the next sample
+    pub fn process_one_sample(&mut self) -> f32 {
+        self.value += self.smoothing_coeff * (self.position - self.value);
+        self.value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_calibration() {
+        let mut pedal = ExpressionPedal::new(44100.0);
+        pedal.set_params(0.0, 0.2, 0.6);
+        pedal.set_cc_value(0.4);
+        assert!(f32::abs(pedal.process_one_sample() - 0.5) < 0.00001);
+        // Past either end of the calibrated range stays at the heel or the toe
+        pedal.set_cc_value(0.1);
+        assert_eq!(pedal.process_one_sample(), 0.0);
+        pedal.set_cc_value(0.9);
+        assert_eq!(pedal.process_one_sample(), 1.0);
+        // A reversed range turns the pedal around
+        pedal.set_params(0.0, 1.0, 0.0);
+        pedal.set_cc_value(0.25);
+        assert_eq!(pedal.process_one_sample(), 0.75);
+    }
+
+    #[test]
+    fn test_smoothing() {
+        let mut pedal = ExpressionPedal::new(1000.0);
+        pedal.set_params(10.0, 0.0, 1.0);
+        pedal.set_position(1.0);
+        let mut previous = 0.5;
+        for _ in 0..10 {
+            let value = pedal.process_one_sample();
+            assert!(value > previous && value < 1.0);
+            previous = value;
+        }
+        // One time constant covers 1 - 1/e of the way
+        assert!(f32::abs(previous - (1.0 - 0.5 * f32::exp(-1.0))) < 0.01);
+        pedal.reset();
+        assert_eq!(pedal.process_one_sample(), 1.0);
+    }
+}
@@ -0,0 +1,590 @@
+use std::sync::Arc;
+
+use realfft::num_complex::Complex;
+use realfft::{ComplexToReal, RealFftPlanner, RealToComplex};
+
+use crate::convolver::PartitionedConvolver;
+use crate::simd::{select_dot_product, DotProduct};
+
+/// Kernels longer than this run through the partitioned FFT convolver instead of purely in direct form
+pub const PARTITIONED_CROSSOVER_TAPS: usize = 256;
+/// Partition size of the FFT convolver, which is also the number of taps that are still run in direct form
+const PARTITION_SIZE: usize = 64;
+/// The cepstrum is computed with an FFT this many times longer than the kernel, which keeps its time aliasing negligible
+const CEPSTRUM_OVERSAMPLING: usize = 8;
+/// Floor for the magnitude response before taking its logarithm, about -200 dB, so the stop band's zeros stay finite
+const MIN_MAGNITUDE: f64 = 1e-10;
+
+/// Window applied to the windowed-sinc kernel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Window {
+    Hamming,
+    Hann,
+    Blackman,
+    BlackmanHarris,
+    /// Kaiser window with the given beta, higher values trade a wider transition band for less ripple
+    Kaiser(f64),
+}
+
+impl Window {
+    /// Return the value of the window at tap ```i``` of a ```num_taps``` long kernel
+    pub(crate) fn value(&self, i: usize, num_taps: usize) -> f64 {
+        let phase = 2.0 * std::f64::consts::PI * i as f64 / (num_taps.max(2) - 1) as f64;
+        match *self {
+            Window::Hamming => 0.54 - 0.46 * f64::cos(phase),
+            Window::Hann => 0.5 - 0.5 * f64::cos(phase),
+            Window::Blackman => 0.42 - 0.5 * f64::cos(phase) + 0.08 * f64::cos(2.0 * phase),
+            Window::BlackmanHarris => {
+                0.35875 - 0.48829 * f64::cos(phase) + 0.14128 * f64::cos(2.0 * phase) - 0.01168 * f64::cos(3.0 * phase)
+            }
+            Window::Kaiser(beta) => {
+                // Position within the window, from -1.0 at the first tap to 1.0 at the last
+                let x = phase / std::f64::consts::PI - 1.0;
+                bessel_i0(beta * f64::sqrt((1.0 - x * x).max(0.0))) / bessel_i0(beta)
+            }
+        }
+    }
+}
+
+/// Zeroth order modified Bessel function of the first kind, evaluated with its power series
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let mut k = 1.0;
+    while term > sum * 1e-12 {
+        term *= (x / (2.0 * k)) * (x / (2.0 * k));
+        sum += term;
+        k += 1.0;
+    }
+    sum
+}
+
+/// Fills ```taps``` with the convolution kernel of an FIR bandpass filter
+/// within the frequency band between ```f_low``` and ```f_high```, the kernel is as long as ```taps```
+pub fn bandpass_fir(taps: &mut [f64], f_low: f64, f_high: f64, sample_rate: f64, window: Window) {
+    let num_taps = taps.len();
+    let center = num_taps / 2;
+    let fl = f_low / sample_rate;
+    let fh = f_high / sample_rate;
+    for i in 0..num_taps {
+        let n = i as f64 - center as f64;
+
+        // Avoid division by zero in the sinc function calculation
+        if n == 0.0 {
+            taps[i] = 2.0 * (fh - fl);
+        } else {
+            taps[i] = (2.0 * fh * (f64::sin(2.0 * std::f64::consts::PI * fh * n) / (2.0 * std::f64::consts::PI * fh * n))) -
+                (2.0 * fl * (f64::sin(2.0 * std::f64::consts::PI * fl * n) / (2.0 * std::f64::consts::PI * fl * n)));
+        }
+
+        // Apply the window to the sinc function
+        taps[i] *= window.value(i, num_taps);
+    }
+}
+
+/// Forward and inverse real FFT of one size
+type FftPlan = (Arc<dyn RealToComplex<f64>>, Arc<dyn ComplexToReal<f64>>);
+
+/// Converts kernels to minimum phase with the real cepstrum
+///
+/// The result has the same magnitude response as the kernel it was made from, but its energy is packed at the start
+/// instead of being centered, so the filter responds straight away instead of ```num_taps / 2``` samples late.
+/// FFT plans and buffers for every length up to ```max_taps``` are created up front, so converting does not allocate.
+pub struct MinimumPhase {
+    plans: Vec<FftPlan>,  // Indexed by the log2 of the FFT size
+    time_buffer: Vec<f64>,
+    spectrum: Vec<Complex<f64>>,
+    scratch: Vec<Complex<f64>>,
+}
+
+impl MinimumPhase {
+    /// Creates a converter for kernels of up to ```max_taps``` taps
+    pub fn new(max_taps: usize) -> Self {
+        let max_fft_size = Self::fft_size(max_taps);
+        let mut planner = RealFftPlanner::<f64>::new();
+        let mut plans = Vec::new();
+        let mut scratch_len = 0;
+        for log2_size in 0..=max_fft_size.trailing_zeros() {
+            let forward = planner.plan_fft_forward(1 << log2_size);
+            let inverse = planner.plan_fft_inverse(1 << log2_size);
+            scratch_len = scratch_len.max(forward.get_scratch_len()).max(inverse.get_scratch_len());
+            plans.push((forward, inverse));
+        }
+
+        MinimumPhase {
+            plans,
+            time_buffer: vec![0.0; max_fft_size],
+            spectrum: vec![Complex::new(0.0, 0.0); max_fft_size / 2 + 1],
+            scratch: vec![Complex::new(0.0, 0.0); scratch_len],
+        }
+    }
+
+    fn fft_size(num_taps: usize) -> usize {
+        (num_taps.max(2) * CEPSTRUM_OVERSAMPLING).next_power_of_two()
+    }
+
+    /// Replaces ```kernel``` with its minimum-phase equivalent
+    pub fn convert(&mut self, kernel: &mut [f64]) {
+        let fft_size = Self::fft_size(kernel.len());
+        assert!(fft_size <= self.time_buffer.len());
+        let (forward, inverse) = &self.plans[fft_size.trailing_zeros() as usize];
+        let time_buffer = &mut self.time_buffer[..fft_size];
+        let spectrum = &mut self.spectrum[..fft_size / 2 + 1];
+        let num_bins = spectrum.len();
+
+        // Real cepstrum: the inverse transform of the log magnitude
+        time_buffer.fill(0.0);
+        time_buffer[..kernel.len()].copy_from_slice(kernel);
+        forward.process_with_scratch(time_buffer, spectrum, &mut self.scratch).expect("buffer sizes match the FFT plan");
+        for bin in spectrum.iter_mut() {
+            *bin = Complex::new(f64::ln(bin.norm().max(MIN_MAGNITUDE)), 0.0);
+        }
+        inverse.process_with_scratch(spectrum, time_buffer, &mut self.scratch).expect("buffer sizes match the FFT plan");
+
+        // Fold the anti-causal half of the cepstrum onto the causal half, the inverse transform is not normalised
+        let scale = 1.0 / fft_size as f64;
+        time_buffer[0] *= scale;
+        for value in time_buffer[1..fft_size / 2].iter_mut() {
+            *value *= 2.0 * scale;
+        }
+        time_buffer[fft_size / 2] *= scale;
+        time_buffer[fft_size / 2 + 1..].fill(0.0);
+
+        // Back to a spectrum with the original magnitude and minimum phase, then to the time domain
+        forward.process_with_scratch(time_buffer, spectrum, &mut self.scratch).expect("buffer sizes match the FFT plan");
+        for bin in spectrum.iter_mut() {
+            *bin = Complex::from_polar(f64::exp(bin.re), bin.im);
+        }
+        spectrum[0].im = 0.0;
+        spectrum[num_bins - 1].im = 0.0;
+        inverse.process_with_scratch(spectrum, time_buffer, &mut self.scratch).expect("buffer sizes match the FFT plan");
+
+        for (tap, value) in kernel.iter_mut().zip(time_buffer.iter()) {
+            *tap = value * scale;
+        }
+    }
+}
+
+/// FIR filter that crossfades between kernels
+///
+/// Whenever a new kernel is loaded the old and new kernels run in parallel and the output is faded from one to the other
+/// over ```crossfade_length``` samples. Because the filter is linear in its taps, this is the same as interpolating the
+/// coefficients per sample, so a kernel change never produces a step in the output regardless of the block size.
+///
+/// Short kernels run in direct form. Kernels longer than ```PARTITIONED_CROSSOVER_TAPS``` only run their first partition
+/// in direct form and hand the rest to a ```PartitionedConvolver```, which keeps long kernels cheap without adding latency.
+///
+/// The direct-form taps are converted to f32 once per kernel, so the inner loop is a plain f32 dot product
+/// that runs on the widest vector instructions the CPU supports.
+///
+/// Every buffer is sized for ```max_taps``` up front, so kernels of any length up to that can be loaded at any time,
+/// including in the middle of a crossfade, without reallocating or losing the input history.
+pub struct FirFilter {
+    max_taps: usize,
+    head_length: usize,  // Number of leading taps of the current kernel that run in direct form
+    previous_head_length: usize,
+    history: Vec<f32>,  // The last inputs, stored twice so the window is always one contiguous slice
+    write_index: usize,
+    taps: Vec<f32>,  // Direct-form part of the current kernel, time-reversed and aligned to the end so it lines up with the oldest-first history window
+    previous_taps: Vec<f32>,  // Kernel that is being faded out
+    crossfade_length: usize,
+    crossfade_position: usize,  // Equal to crossfade_length once the fade has finished
+    convolver: Option<PartitionedConvolver>,  // Runs everything after the head of long kernels
+    dot_product: DotProduct,
+}
+
+impl FirFilter {
+    /// Creates a silent filter with room for kernels of up to ```max_taps``` taps
+    pub fn new(max_taps: usize, crossfade_length: usize) -> Self {
+        let history_length = max_taps.min(PARTITIONED_CROSSOVER_TAPS);
+        let convolver = if max_taps > PARTITIONED_CROSSOVER_TAPS {
+            Some(PartitionedConvolver::new(max_taps - PARTITION_SIZE, PARTITION_SIZE))
+        } else {
+            None
+        };
+        FirFilter {
+            max_taps,
+            head_length: 0,
+            previous_head_length: 0,
+            history: vec![0.0; 2 * history_length],
+            write_index: 0,
+            taps: vec![0.0; history_length],
+            previous_taps: vec![0.0; history_length],
+            crossfade_length,
+            crossfade_position: crossfade_length,
+            convolver,
+            dot_product: select_dot_product(),
+        }
+    }
+
+    /// Clears the input history and finishes any running crossfade
+    pub fn reset(&mut self) {
+        self.history.fill(0.0);
+        self.write_index = 0;
+        self.crossfade_position = self.crossfade_length;
+        if let Some(convolver) = self.convolver.as_mut() {
+            convolver.reset();
+        }
+    }
+
+    /// Drops the kernel as well, so the next one fades in from silence like in a new filter
+    pub fn clear_kernel(&mut self) {
+        self.reset();
+        self.head_length = 0;
+        self.previous_head_length = 0;
+        self.taps.fill(0.0);
+        self.previous_taps.fill(0.0);
+        if let Some(convolver) = self.convolver.as_mut() {
+            convolver.clear_kernels();
+        }
+    }
+
+    /// Sets how many samples a kernel change is spread over, 0 switches instantly
+    pub fn set_crossfade_length(&mut self, crossfade_length: usize) {
+        if crossfade_length != self.crossfade_length {
+            // Keep the same progress through a running fade
+            self.crossfade_position = (self.crossfade_position * crossfade_length)
+                .checked_div(self.crossfade_length)
+                .unwrap_or(crossfade_length);
+            self.crossfade_length = crossfade_length;
+        }
+    }
+
+    /// Loads a kernel generated by ```bandpass_fir```, of any length up to the filter's maximum
+    /// The output starts moving from whatever is currently heard towards the new kernel
+    pub fn set_kernel(&mut self, kernel: &[f64]) {
+        assert!(kernel.len() <= self.max_taps);
+
+        let mix = if self.crossfade_position < self.crossfade_length {
+            self.crossfade_position as f32 / self.crossfade_length as f32
+        } else {
+            1.0
+        };
+        if mix < 1.0 {
+            // Interrupting a fade, so start the next one from the blend that is currently heard
+            // Taps past a kernel's length are zero, so kernels of different lengths blend correctly
+            for (previous, current) in self.previous_taps.iter_mut().zip(self.taps.iter()) {
+                *previous += (*current - *previous) * mix;
+            }
+            self.previous_head_length = self.previous_head_length.max(self.head_length);
+        } else {
+            std::mem::swap(&mut self.previous_taps, &mut self.taps);
+            self.previous_head_length = self.head_length;
+        }
+
+        let history_length = self.taps.len();
+        let (head, tail) = if kernel.len() > PARTITIONED_CROSSOVER_TAPS {
+            kernel.split_at(PARTITION_SIZE)
+        } else {
+            (kernel, &kernel[kernel.len()..])
+        };
+        self.head_length = head.len();
+        self.taps.fill(0.0);
+        for (tap, &coefficient) in self.taps[history_length - self.head_length..].iter_mut().zip(head.iter().rev()) {
+            *tap = coefficient as f32;
+        }
+        if let Some(convolver) = self.convolver.as_mut() {
+            convolver.set_kernel(tail, mix);
+        }
+        self.crossfade_position = 0;
+    }
+
+    /// Convolves ```block``` with the kernel in place
+    pub fn process_block(&mut self, block: &mut [f32]) {
+        let history_length = self.taps.len();
+        for sample in block.iter_mut() {
+            let input = *sample;
+            self.history[self.write_index] = input;
+            self.history[self.write_index + history_length] = input;
+            self.write_index = (self.write_index + 1) % history_length;
+            let window = &self.history[self.write_index..self.write_index + history_length];
+
+            let (tail, previous_tail) = match self.convolver.as_ref() {
+                Some(convolver) => convolver.tail(),
+                None => (0.0, 0.0),
+            };
+            let start = history_length - self.head_length;
+            let output = (self.dot_product)(&window[start..], &self.taps[start..]) + tail;
+            if self.crossfade_position < self.crossfade_length {
+                let mix = self.crossfade_position as f32 / self.crossfade_length as f32;
+                let start = history_length - self.previous_head_length;
+                let previous_output = (self.dot_product)(&window[start..], &self.previous_taps[start..]) + previous_tail;
+                *sample = previous_output + (output - previous_output) * mix;
+                self.crossfade_position += 1;
+            } else {
+                *sample = output;
+            }
+
+            if let Some(convolver) = self.convolver.as_mut() {
+                convolver.push_sample(input, self.crossfade_position < self.crossfade_length);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f32 = 0.00001;
+
+    /// Designs a ```num_taps``` long kernel with ```bandpass_fir```
+    fn bandpass_kernel(num_taps: usize, f_low: f64, f_high: f64, sample_rate: f64, window: Window) -> Vec<f64> {
+        let mut taps = vec![0.0; num_taps];
+        bandpass_fir(&mut taps, f_low, f_high, sample_rate, window);
+        taps
+    }
+
+    /// Reference convolution of the whole signal with a fixed kernel
+    fn convolve(input: &[f32], kernel: &[f64]) -> Vec<f32> {
+        (0..input.len())
+            .map(|n| (0..kernel.len()).filter(|&k| k <= n).map(|k| input[n - k] * kernel[k] as f32).sum())
+            .collect()
+    }
+
+    fn noise(length: usize) -> Vec<f32> {
+        let mut state = 12345u32;
+        (0..length)
+            .map(|_| {
+                state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+                (state >> 8) as f32 / (1u32 << 24) as f32 * 2.0 - 1.0
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_matches_reference_convolution() {
+        // Asymmetric kernel so a reversed convolution would fail
+        let kernel: Vec<f64> = (0..31).map(|i| (i as f64 * 0.37).sin() / (i as f64 + 1.0)).collect();
+        let input = noise(1000);
+        let mut filter = FirFilter::new(kernel.len(), 0);
+        filter.set_kernel(&kernel);
+        let mut output = input.clone();
+        filter.process_block(&mut output);
+
+        let expected = convolve(&input, &kernel);
+        for i in 0..input.len() {
+            assert!(f32::abs(output[i] - expected[i]) < EPSILON);
+        }
+    }
+
+    #[test]
+    fn test_block_size_independent() {
+        let kernel = bandpass_kernel(101, 300.0, 3000.0, 44100.0, Window::Hamming);
+        let input = noise(4096);
+
+        let mut reference = input.clone();
+        let mut filter = FirFilter::new(101, 64);
+        filter.set_kernel(&kernel);
+        filter.process_block(&mut reference);
+
+        for block_size in [1, 7, 64, 100, 513] {
+            let mut filter = FirFilter::new(101, 64);
+            filter.set_kernel(&kernel);
+            let mut output = input.clone();
+            for block in output.chunks_mut(block_size) {
+                filter.process_block(block);
+            }
+            for i in 0..input.len() {
+                assert!(f32::abs(output[i] - reference[i]) < EPSILON);
+            }
+        }
+    }
+
+    #[test]
+    fn test_crossfade_between_kernels() {
+        let old_kernel = bandpass_kernel(51, 100.0, 500.0, 44100.0, Window::Hamming);
+        let new_kernel = bandpass_kernel(51, 2000.0, 6000.0, 44100.0, Window::Hamming);
+        let input = noise(600);
+        let old_output = convolve(&input, &old_kernel);
+        let new_output = convolve(&input, &new_kernel);
+
+        let crossfade_length = 100;
+        let mut filter = FirFilter::new(51, 0);
+        filter.set_kernel(&old_kernel);
+        filter.set_crossfade_length(crossfade_length);
+        let mut output = input.clone();
+        filter.process_block(&mut output[..200]);
+        filter.set_kernel(&new_kernel);
+        filter.process_block(&mut output[200..]);
+
+        for i in 0..200 {
+            assert!(f32::abs(output[i] - old_output[i]) < EPSILON);
+        }
+        for i in 200..200 + crossfade_length {
+            let mix = (i - 200) as f32 / crossfade_length as f32;
+            let expected = old_output[i] * (1.0 - mix) + new_output[i] * mix;
+            assert!(f32::abs(output[i] - expected) < EPSILON);
+        }
+        for i in 200 + crossfade_length..600 {
+            assert!(f32::abs(output[i] - new_output[i]) < EPSILON);
+        }
+    }
+
+    #[test]
+    fn test_partitioned_matches_reference_convolution() {
+        let kernel: Vec<f64> = (0..1000).map(|i| (i as f64 * 0.37).sin() / (i as f64 + 1.0)).collect();
+        let input = noise(4096);
+        let expected = convolve(&input, &kernel);
+
+        for block_size in [1, 63, 64, 100, 512] {
+            let mut filter = FirFilter::new(kernel.len(), 0);
+            assert!(filter.convolver.is_some());
+            filter.set_kernel(&kernel);
+            let mut output = input.clone();
+            for block in output.chunks_mut(block_size) {
+                filter.process_block(block);
+            }
+            for i in 0..input.len() {
+                assert!(f32::abs(output[i] - expected[i]) < 0.0001);
+            }
+        }
+    }
+
+    #[test]
+    fn test_partitioned_crossfade_between_kernels() {
+        let old_kernel = bandpass_kernel(511, 100.0, 500.0, 44100.0, Window::Hamming);
+        let new_kernel = bandpass_kernel(511, 2000.0, 6000.0, 44100.0, Window::Hamming);
+        let input = noise(2000);
+        let old_output = convolve(&input, &old_kernel);
+        let new_output = convolve(&input, &new_kernel);
+
+        // Switch kernels twice in the middle of a partition, the second time while still fading
+        let crossfade_length = 300;
+        let mut filter = FirFilter::new(511, crossfade_length);
+        filter.set_kernel(&old_kernel);
+        let mut output = input.clone();
+        filter.process_block(&mut output[..1000]);
+        filter.set_kernel(&new_kernel);
+        filter.process_block(&mut output[1000..1100]);
+        filter.set_kernel(&new_kernel);
+        filter.process_block(&mut output[1100..]);
+
+        for i in 300..1000 {
+            assert!(f32::abs(output[i] - old_output[i]) < 0.0001);
+        }
+        for i in 1000..1100 {
+            let mix = (i - 1000) as f32 / crossfade_length as f32;
+            let expected = old_output[i] * (1.0 - mix) + new_output[i] * mix;
+            assert!(f32::abs(output[i] - expected) < 0.0001);
+        }
+        for i in 1100 + crossfade_length..2000 {
+            assert!(f32::abs(output[i] - new_output[i]) < 0.0001);
+        }
+    }
+
+    #[test]
+    fn test_kernel_length_changes_keep_history() {
+        let short_kernel = bandpass_kernel(101, 300.0, 3000.0, 44100.0, Window::Hann);
+        let long_kernel = bandpass_kernel(1001, 300.0, 3000.0, 44100.0, Window::Kaiser(8.0));
+        let tiny_kernel = bandpass_kernel(31, 300.0, 3000.0, 44100.0, Window::Blackman);
+        let input = noise(3000);
+        let short_output = convolve(&input, &short_kernel);
+        let long_output = convolve(&input, &long_kernel);
+        let tiny_output = convolve(&input, &tiny_kernel);
+
+        let crossfade_length = 200;
+        let mut filter = FirFilter::new(1001, crossfade_length);
+        filter.set_kernel(&short_kernel);
+        let mut output = input.clone();
+        for block in output[..1500].chunks_mut(100) {
+            filter.process_block(block);
+        }
+        filter.set_kernel(&long_kernel);
+        filter.process_block(&mut output[1500..2500]);
+        filter.set_crossfade_length(0);
+        filter.set_kernel(&tiny_kernel);
+        filter.process_block(&mut output[2500..]);
+
+        for i in crossfade_length..1500 {
+            assert!(f32::abs(output[i] - short_output[i]) < 0.0001);
+        }
+        for i in 1500..1500 + crossfade_length {
+            let mix = (i - 1500) as f32 / crossfade_length as f32;
+            let expected = short_output[i] * (1.0 - mix) + long_output[i] * mix;
+            assert!(f32::abs(output[i] - expected) < 0.0001);
+        }
+        // The full history is available, so the longer kernel is exact straight away
+        for i in 1500 + crossfade_length..2500 {
+            assert!(f32::abs(output[i] - long_output[i]) < 0.0001);
+        }
+        for i in 2500..3000 {
+            assert!(f32::abs(output[i] - tiny_output[i]) < 0.0001);
+        }
+    }
+
+    #[test]
+    fn test_windows() {
+        let windows = [Window::Hamming, Window::Hann, Window::Blackman, Window::BlackmanHarris, Window::Kaiser(8.6)];
+        for window in windows {
+            for i in 0..101 {
+                assert!(f64::abs(window.value(i, 101) - window.value(100 - i, 101)) < 1e-9);
+            }
+            assert!(f64::abs(window.value(50, 101) - 1.0) < 1e-9);
+            assert!(window.value(0, 101) < 0.1);
+        }
+        assert!(f64::abs(Window::Hamming.value(0, 101) - 0.08) < 1e-9);
+        assert!(f64::abs(Window::Hann.value(0, 101)) < 1e-9);
+        for i in 0..101 {
+            assert!(f64::abs(Window::Kaiser(0.0).value(i, 101) - 1.0) < 1e-9);
+        }
+    }
+
+    /// Magnitude response of ```kernel``` at ```freq``` in Hz
+    fn magnitude(kernel: &[f64], freq: f64, sample_rate: f64) -> f64 {
+        let omega = 2.0 * std::f64::consts::PI * freq / sample_rate;
+        let (re, im) = kernel.iter().enumerate().fold((0.0, 0.0), |(re, im), (n, tap)| {
+            (re + tap * f64::cos(omega * n as f64), im - tap * f64::sin(omega * n as f64))
+        });
+        f64::sqrt(re * re + im * im)
+    }
+
+    #[test]
+    fn test_minimum_phase_keeps_magnitude() {
+        let mut converter = MinimumPhase::new(511);
+        for num_taps in [15, 101, 511] {
+            let linear = bandpass_kernel(num_taps, 500.0, 2000.0, 44100.0, Window::Blackman);
+            let mut minimum = linear.clone();
+            converter.convert(&mut minimum);
+            for freq in [100.0, 500.0, 1000.0, 2000.0, 5000.0, 15000.0] {
+                assert!(f64::abs(magnitude(&linear, freq, 44100.0) - magnitude(&minimum, freq, 44100.0)) < 0.01);
+            }
+        }
+    }
+
+    #[test]
+    fn test_minimum_phase_removes_delay() {
+        let mut converter = MinimumPhase::new(255);
+        let linear = bandpass_kernel(255, 500.0, 2000.0, 44100.0, Window::Hamming);
+        let mut minimum = linear.clone();
+        converter.convert(&mut minimum);
+
+        // Center of the energy, which is at (num_taps - 1) / 2 for the linear-phase kernel
+        let energy_center = |kernel: &[f64]| {
+            let energy: f64 = kernel.iter().map(|tap| tap * tap).sum();
+            kernel.iter().enumerate().map(|(n, tap)| n as f64 * tap * tap).sum::<f64>() / energy
+        };
+        assert!(f64::abs(energy_center(&linear) - 127.0) < 0.001);
+        assert!(energy_center(&minimum) < energy_center(&linear) / 2.0);
+    }
+
+    #[test]
+    fn test_interrupted_crossfade_is_continuous() {
+        let kernels = [
+            bandpass_kernel(51, 100.0, 500.0, 44100.0, Window::Hamming),
+            bandpass_kernel(51, 2000.0, 6000.0, 44100.0, Window::Hamming),
+            bandpass_kernel(51, 800.0, 1200.0, 44100.0, Window::Hamming),
+        ];
+        let mut filter = FirFilter::new(51, 256);
+        let mut output = vec![1.0; 1000];
+
+        // New kernel every 10 samples, much faster than the fade, the output must stay smooth
+        for (i, block) in output.chunks_mut(10).enumerate() {
+            filter.set_kernel(&kernels[i % 3]);
+            filter.process_block(block);
+        }
+        for i in 100..output.len() {
+            assert!(f32::abs(output[i] - output[i - 1]) < 0.01);
+        }
+    }
+}
@@ -1,57 +1,181 @@
-use crate::ring_buffer::RingBuffer;
+/// Time constant of the smoothing applied to shapes with jumps, in seconds
+/// Long enough that a jump sweeps the filter instead of clicking it, short enough to keep the edge sharp to the ear
+const SMOOTHING_TIME: f32 = 0.003;
+/// Curvature of the pedal rock shape, higher values linger longer at the heel
+const PEDAL_ROCK_CURVE: f32 = 3.0;
+
+/// Waveform of the LFO, every shape runs between -1.0 and 1.0
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shape {
+    Sine,
+    Triangle,
+    SawUp,
+    SawDown,
+    /// Square wave that is high for the given fraction of each cycle
+    Square(f32),
+    /// Rocks between heel and toe like a foot on a pedal, lingering at the heel and rushing through the top
+    PedalRock,
+    /// Glides to a new random value every cycle
+    SmoothRandom,
+}
+
+impl Shape {
+    /// Return the value of the shape at ```phase```, from 0.0 at the start of a cycle to 1.0 at its end
+    /// For ```SmoothRandom``` this is instead how far the glide from one random value to the next has got, from 0.0 to 1.0
+    pub fn value(&self, phase: f32) -> f32 {
+        match *self {
+            Shape::Sine => f32::sin(phase * 2.0 * std::f32::consts::PI),
+            // Starts at 0.0 and rises first, like the sine
+            Shape::Triangle => {
+                if phase < 0.25 {
+                    4.0 * phase
+                } else if phase < 0.75 {
+                    2.0 - 4.0 * phase
+                } else {
+                    4.0 * phase - 4.0
+                }
+            }
+            Shape::SawUp => 2.0 * phase - 1.0,
+            Shape::SawDown => 1.0 - 2.0 * phase,
+            Shape::Square(pulse_width) => if phase < pulse_width { 1.0 } else { -1.0 },
+            Shape::PedalRock => {
+                let position = 1.0 - f32::abs(2.0 * phase - 1.0);
+                2.0 * (f32::exp(PEDAL_ROCK_CURVE * position) - 1.0) / (f32::exp(PEDAL_ROCK_CURVE) - 1.0) - 1.0
+            }
+            Shape::SmoothRandom => 0.5 - 0.5 * f32::cos(phase * std::f32::consts::PI),
+        }
+    }
+
+    /// Whether the shape jumps, and so has to be smoothed before it moves the filter
+    fn is_discontinuous(&self) -> bool {
+        matches!(*self, Shape::SawUp | Shape::SawDown | Shape::Square(_))
+    }
+}
 
 /// LFO is a low frequency oscillator
-/// 
-/// ```buffer``` is used internally to store the samples of a wavetable
-/// ```index``` is incremenated to store the current position in the wavetable
+///
+/// ```table``` stores one cycle of the current shape, with the first sample repeated at the end for interpolation
+/// ```phase``` runs from 0.0 to 1.0 over a cycle, so changing the frequency only changes how fast it moves
 pub struct LFO {
-    buffer: RingBuffer<f32>,
+    table: Vec<f32>,
     freq: f32,
     sample_rate: usize,
-    index: f32
+    phase: f64,
+    shape: Shape,
+    smoothed: f32,  // Output of the smoothing filter, always running so switching shapes does not jump
+    smoothing_coeff: f32,
+    random_state: u32,
+    random_from: f32,  // Random value the current SmoothRandom cycle glides away from
+    random_to: f32,  // Random value the current SmoothRandom cycle glides towards
 }
 
-/// LFO is a low frequency oscillator
-/// 
-/// ```buffer``` is used internally to store the samples of a wavetable
-/// ```index``` is incremenated to store the current position in the wavetable
+/// Number of samples in the single-cycle wavetable
+const TABLE_SIZE: usize = 2048;
+
 impl LFO {
-    /// Creates a new LFO of specified frequency and sample rate
+    /// Creates a new sine LFO of specified frequency and sample rate
     pub fn new(freq: f32, sample_rate: usize) -> Self {
         let mut lfo = LFO {
-            buffer: RingBuffer::new(sample_rate),
+            table: vec![0.0; TABLE_SIZE + 1],
             freq: freq,
             sample_rate: sample_rate,
-            index: 0.0,
+            phase: 0.0,
+            shape: Shape::Sine,
+            smoothed: 0.0,
+            smoothing_coeff: 1.0 - f32::exp(-1.0 / (SMOOTHING_TIME * sample_rate as f32)),
+            random_state: 1,
+            random_from: 0.0,
+            random_to: 0.0,
         };
+        lfo.random_to = lfo.next_random();
 
-        for i in 0..sample_rate {
-            lfo.buffer.push(f32::sin(i as f32 * freq * 2.0 * std::f32::consts::PI / sample_rate as f32));
-        }
+        lfo.fill_wavetable();
         return lfo;
     }
 
+    /// Goes back to the start of a cycle with the random sequence and the smoothing as they were when created
+    pub fn reset(&mut self) {
+        self.phase = 0.0;
+        self.smoothed = 0.0;
+        self.random_state = 1;
+        self.random_from = 0.0;
+        self.random_to = self.next_random();
+    }
+
     /// Places a block of generated LFO samples into ```output```
     pub fn get_block(&mut self, output: &mut [f32]) {
+        let increment = self.freq as f64 / self.sample_rate as f64;
         for i in 0..output.len() {
-            output[i] = self.buffer.get_frac(self.index);
-            self.index += self.freq;
+            let value = if self.shape == Shape::SmoothRandom {
+                // Random values can not be stored in a repeating wavetable, so glide between them live
+                self.random_from + (self.random_to - self.random_from) * self.shape.value(self.phase as f32)
+            } else {
+                self.lookup(self.phase as f32)
+            };
+            self.smoothed += self.smoothing_coeff * (value - self.smoothed);
+            output[i] = if self.shape.is_discontinuous() { self.smoothed } else { value };
 
-            if self.index > self.sample_rate as f32{
-                self.index -= self.sample_rate as f32;
+            self.phase += increment;
+            if self.phase >= 1.0 {
+                self.phase = self.phase.fract();
+                self.random_from = self.random_to;
+                self.random_to = self.next_random();
             }
         }
     }
 
-    /// Changes the frequency of the LFO. This will modify the wavetable
+    /// Changes the frequency of the LFO, keeping its phase so the output does not jump
     pub fn set_freq(&mut self, freq: f32) {
-        if f32::abs(self.freq - freq) > 0.001 {
-            self.freq = freq;
-            self.buffer.reset();
-            for i in 0..self.sample_rate {
-                self.buffer.push(f32::sin(i as f32 * 2.0 * self.freq * std::f32::consts::PI / self.sample_rate as f32));
-            }
-        } 
+        self.freq = freq;
+    }
+
+    /// Return how far the LFO is through its current cycle, from 0.0 to 1.0
+    pub fn phase(&self) -> f32 {
+        self.phase as f32
+    }
+
+    /// Moves the LFO to ```phase``` of its cycle, from 0.0 to 1.0, to line it up with an outside clock
+    pub fn set_phase(&mut self, phase: f32) {
+        self.phase = (phase as f64).rem_euclid(1.0);
+    }
+
+    /// Restarts the LFO from ```phase``` of its cycle, from 0.0 to 1.0, so it can be retriggered by a note
+    /// SmoothRandom starts a new glide from where it is, instead of jumping
+    pub fn reset_phase(&mut self, phase: f32) {
+        if self.shape == Shape::SmoothRandom {
+            self.random_from += (self.random_to - self.random_from) * self.shape.value(self.phase as f32);
+            self.random_to = self.next_random();
+        }
+        self.set_phase(phase);
+    }
+
+    /// Changes the waveform of the LFO. This will modify the wavetable
+    pub fn set_shape(&mut self, shape: Shape) {
+        if shape != self.shape {
+            self.shape = shape;
+            self.fill_wavetable();
+        }
+    }
+
+    fn fill_wavetable(&mut self) {
+        for i in 0..TABLE_SIZE {
+            self.table[i] = self.shape.value(i as f32 / TABLE_SIZE as f32);
+        }
+        self.table[TABLE_SIZE] = self.table[0];
+    }
+
+    /// Return the linearly interpolated wavetable value at ```phase```
+    fn lookup(&self, phase: f32) -> f32 {
+        let position = phase * TABLE_SIZE as f32;
+        let index = (position as usize).min(TABLE_SIZE - 1);
+        let frac = position - index as f32;
+        self.table[index] + frac * (self.table[index + 1] - self.table[index])
+    }
+
+    /// Return a uniformly distributed random value between -1.0 and 1.0
+    fn next_random(&mut self) -> f32 {
+        self.random_state = self.random_state.wrapping_mul(1664525).wrapping_add(1013904223);
+        (self.random_state >> 8) as f32 / (1 << 23) as f32 - 1.0
     }
 }
 
@@ -65,4 +189,102 @@ fn test_lfo() {
     assert!(f32::abs(output[157] - 1.0) < 0.00001); // PI / 2
     assert!(f32::abs(output[314] - 0.0) < 0.00001); // PI
     assert!(f32::abs(output[471] + 1.0) < 0.00001); // 3*PI / 2
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_shapes() {
+    let shapes = [Shape::Sine, Shape::Triangle, Shape::SawUp, Shape::SawDown, Shape::Square(0.25), Shape::PedalRock];
+    for shape in shapes {
+        for i in 0..1000 {
+            let value = shape.value(i as f32 / 1000.0);
+            assert!((-1.0..=1.0).contains(&value));
+        }
+    }
+    assert!(f32::abs(Shape::Triangle.value(0.25) - 1.0) < 0.00001);
+    assert!(f32::abs(Shape::Triangle.value(0.75) + 1.0) < 0.00001);
+    assert_eq!(Shape::SawUp.value(0.0), -1.0);
+    assert_eq!(Shape::SawDown.value(0.0), 1.0);
+    assert_eq!(Shape::Square(0.25).value(0.2), 1.0);
+    assert_eq!(Shape::Square(0.25).value(0.3), -1.0);
+    // Heel at the start of the cycle, toe in the middle, and more time spent below the middle of the sweep
+    assert!(f32::abs(Shape::PedalRock.value(0.0) + 1.0) < 0.00001);
+    assert!(f32::abs(Shape::PedalRock.value(0.5) - 1.0) < 0.00001);
+    assert!(Shape::PedalRock.value(0.25) < 0.0);
+}
+
+#[test]
+fn test_sharp_shapes_are_smoothed() {
+    for shape in [Shape::SawUp, Shape::Square(0.5), Shape::SmoothRandom] {
+        let mut lfo = LFO::new(1.0, 44100);
+        lfo.set_shape(shape);
+        let mut output = vec![0f32; 3 * 44100];
+        lfo.get_block(output.as_mut_slice());
+        for i in 1..output.len() {
+            assert!((-1.0..=1.0).contains(&output[i]));
+            assert!(f32::abs(output[i] - output[i - 1]) < 0.02);
+        }
+    }
+}
+
+#[test]
+fn test_freq_is_in_hz() {
+    // Two cycles a second reach the first peak after an eighth of a second
+    let mut lfo = LFO::new(2.0, 1000);
+    let mut output = vec![0f32; 1000];
+    lfo.get_block(output.as_mut_slice());
+
+    assert!(f32::abs(output[125] - 1.0) < 0.00001);
+    assert!(f32::abs(output[375] + 1.0) < 0.00001);
+    assert!(f32::abs(output[500] - 0.0) < 0.00001);
+}
+
+#[test]
+fn test_set_freq_keeps_phase() {
+    let mut lfo = LFO::new(1.0, 1000);
+    let mut output = vec![0f32; 100];
+    lfo.get_block(output.as_mut_slice());
+    let phase = lfo.phase();
+    assert!(f32::abs(phase - 0.1) < 0.00001);
+
+    // Jumping to a much faster rate continues from the same point of the cycle
+    lfo.set_freq(10.0);
+    assert_eq!(lfo.phase(), phase);
+    let last = output[99];
+    lfo.get_block(output.as_mut_slice());
+    assert!(f32::abs(output[0] - f32::sin(phase * 2.0 * std::f32::consts::PI)) < 0.0001);
+    assert!(f32::abs(output[0] - last) < 0.01);
+    assert!(f32::abs(lfo.phase() - 0.1) < 0.0001);
+}
+
+#[test]
+fn test_frequency_sweep_is_continuous() {
+    // Automation moving the rate every sample, the largest step is that of the fastest rate
+    let mut lfo = LFO::new(0.1, 44100);
+    let mut previous = 0.0;
+    let mut output = [0f32; 1];
+    for i in 0..44100 {
+        lfo.set_freq(0.1 + 20.0 * i as f32 / 44100.0);
+        lfo.get_block(&mut output);
+        assert!(f32::abs(output[0] - previous) < 2.0 * std::f32::consts::PI * 20.1 / 44100.0 + 0.0001);
+        previous = output[0];
+    }
+}
+
+#[test]
+fn test_reset_phase() {
+    let mut lfo = LFO::new(1.0, 1000);
+    let mut output = vec![0f32; 300];
+    lfo.get_block(output.as_mut_slice());
+    lfo.reset_phase(0.25);
+    assert_eq!(lfo.phase(), 0.25);
+    lfo.get_block(output.as_mut_slice());
+    assert!(f32::abs(output[0] - 1.0) < 0.00001);
+
+    // A retriggered random glide carries on from its current value
+    lfo.set_shape(Shape::SmoothRandom);
+    lfo.get_block(output.as_mut_slice());
+    let last = output[299];
+    lfo.reset_phase(0.0);
+    lfo.get_block(output.as_mut_slice());
+    assert!(f32::abs(output[0] - last) < 0.01);
+}
@@ -2,26 +2,364 @@ use std::sync::Arc;
 
 use nih_plug::prelude::*;
 
-use envelope::Envelope;
+use envelope::{Curve, Envelope};
+use envelope_follower::{Detector, EnvelopeFollower};
+use expression_pedal::ExpressionPedal;
 use vibrato::Vibrato;
 use nih_plug_egui::{create_egui_editor, egui, widgets, EguiState};
 
-use crate::lfo::LFO;
+use crate::fir::{bandpass_fir, FirFilter, MinimumPhase, Window};
+use crate::lfo::{Shape, LFO};
+use crate::onset::{Method, OnsetDetector};
+use crate::modulation::{limit_band, modulate_exponential, modulate_linear, modulate_octaves, modulate_soft_clamp};
+use crate::oversampler::Oversampler;
+use crate::svf::{center_q_to_edges, edges_to_center_q, StateVariableFilter};
+use crate::wah_pedal::WahPedal;
 
 mod vibrato;
 mod ring_buffer;
 mod lfo;
 mod envelope;
+mod envelope_follower;
+mod expression_pedal;
+mod convolver;
+pub mod fir;
+mod svf;
+mod wah_pedal;
+mod oversampler;
+mod modulation;
+mod onset;
+pub mod simd;
+
+/// Longest FIR kernel the Filter Length parameter allows, the FIR filters are allocated for this many taps
+const MAX_FILTER_LENGTH: usize = 4095;
+/// Number of samples the Linear Phase and Minimum Phase filters keep a kernel for before following the LFO again
+const KERNEL_UPDATE_INTERVAL: usize = 128;
+/// Longest kernel the Minimum Phase filter uses, whatever the Filter Length
+/// Converting a kernel takes four FFTs of eight times its length every ```KERNEL_UPDATE_INTERVAL``` samples while the
+/// band moves, which at 4095 taps takes longer than the audio it is for (see the minimum_phase_update benchmark)
+const MAX_MINIMUM_PHASE_LENGTH: usize = 1023;
+/// How far in octaves a band edge has to move before the Minimum Phase filter designs a new kernel, a quarter of a
+/// semitone, the cepstral conversion is too costly to run on every kernel update
+const MINIMUM_PHASE_TOLERANCE: f32 = 1.0 / 48.0;
 
 /// Main struct of the Wah Wah plugin
 struct Wahwah {
     params: Arc<WahwahParams>,
     envelope: Envelope,
-    lfo: LFO,
-    previous_samples_list: Vec<Vec<f32>>,  // Buffer for storing the last N-1 samples between process calls
+    onset_detector: OnsetDetector,  // Finds onsets in the mix of all channels for the spectral onset methods
+    follower_list: Vec<EnvelopeFollower>,  // One envelope follower per channel
+    lfo_list: Vec<LFO>,  // One LFO per channel, the later ones follow the first one's phase plus the stereo offset
+    fir_list: Vec<FirFilter>,  // One FIR filter per channel, each keeps its own input history between process calls
+    svf_list: Vec<StateVariableFilter>,  // One state-variable filter per channel
+    pedal_list: Vec<WahPedal>,  // One wah circuit model per channel
+    oversampler_list: Vec<Oversampler>,  // One oversampler per channel
+    oversampled: Vec<f32>,  // One channel of the current block at the oversampled rate, sized for 8x the largest block
+    oversampling_stages: usize,  // Number of 2x stages the filters are currently tuned for
+    minimum_phase: MinimumPhase,  // Shared by all channels, only used while their kernels are designed
+    latency: u32,  // Latency last reported to the host, in samples
+    lfo_values: Vec<f32>,  // LFO output of one channel for the current block, sized for the largest block in initialize
+    stereo_offsets: Vec<f32>,  // Smoothed Stereo Phase for each sample of the current block, as a fraction of a cycle
+    stereo_offset: f32,  // Fraction of a cycle each LFO is currently behind the one before it
+    env_values: Vec<Vec<f32>>,  // Envelope of each channel for the current block, sized like ```lfo_values```
+    onsets: Vec<bool>,  // Whether the envelope detected an onset at each sample of the current block
+    sidechain: Vec<Vec<f32>>,  // Sidechain input of the current block for each channel, silent when none is connected
+    detector_input: Vec<Vec<f32>>,  // What the envelope and the onset detector listen to, for each channel
+    expression_pedal: ExpressionPedal,  // Shared by all channels, there is only one foot
+    pedal_position: f32,  // Pedal Position last handed to ```expression_pedal```, so only changes to it move the pedal
+    pedal_events: Vec<(u32, f32)>,  // Timing and value of the pedal CC events in the current block, allocated in prepare
+    pedal_values: Vec<f32>,  // Smoothed pedal position for the current block, sized like ```lfo_values```
+    kernel: Vec<f64>,  // Scratch space the FIR kernels are designed in
+    kernel_designs: Vec<Option<KernelDesign>>,  // What each channel's FIR kernel was last designed for, if anything
+    tempo: Option<f64>,  // Host tempo in BPM, if the host reports one
+    song_position: Option<f64>,  // Host position in quarter notes, only while the transport is playing
     sample_rate: f64
 }
 
+/// Everything a FIR kernel is designed from
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct KernelDesign {
+    f_low: f32,
+    f_high: f32,
+    num_taps: usize,
+    window: Window,
+    filter_mode: FilterMode,
+    filter_rate: f64,
+}
+
+impl KernelDesign {
+    /// Return whether a kernel designed for ```self``` can stand in for one designed for ```other```, which is when
+    /// both are Minimum Phase kernels of the same length and their band edges are within ```MINIMUM_PHASE_TOLERANCE```
+    fn close_to(&self, other: &KernelDesign) -> bool {
+        let octaves_apart = |a: f32, b: f32| f32::abs(f32::log2(a / b));
+        self.filter_mode == FilterMode::MinimumPhase
+            && self.num_taps == other.num_taps
+            && self.window == other.window
+            && self.filter_mode == other.filter_mode
+            && self.filter_rate == other.filter_rate
+            && octaves_apart(self.f_low, other.f_low) < MINIMUM_PHASE_TOLERANCE
+            && octaves_apart(self.f_high, other.f_high) < MINIMUM_PHASE_TOLERANCE
+    }
+}
+
+/// Selects what drives the envelope that deepens the sweep
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+enum EnvelopeMode {
+    /// Attack, decay, sustain and release, started by each onset and released when the input falls away
+    Onset,
+    /// Follows the level of the input, for touch-wah
+    Follower,
+}
+
+/// Selects what finds the onsets that start the envelope in the Onset envelope mode
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+enum OnsetMethod {
+    /// The input level crossing the Onset Threshold
+    Level,
+    /// Rises across the whole spectrum, also finds soft notes and ignores sustained distortion
+    #[name = "Spectral Flux"]
+    SpectralFlux,
+    /// Rises of the high frequencies, follows pick attacks
+    #[name = "HFC"]
+    HighFrequencyContent,
+}
+
+/// Shape of a stage of the onset envelope
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+enum EnvelopeCurve {
+    Linear,
+    /// Rises slowly and falls quickly at first
+    Exponential,
+    /// Rises quickly and falls slowly at first
+    Logarithmic,
+}
+
+impl EnvelopeCurve {
+    fn curve(self) -> Curve {
+        match self {
+            EnvelopeCurve::Linear => Curve::Linear,
+            EnvelopeCurve::Exponential => Curve::Exponential,
+            EnvelopeCurve::Logarithmic => Curve::Logarithmic,
+        }
+    }
+}
+
+/// Selects what the envelope moves
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+enum EnvelopeTarget {
+    /// Moves the band itself, up or down, like a classic envelope filter
+    #[name = "Filter Center"]
+    FilterCenter,
+    /// Widens or narrows the LFO sweep
+    #[name = "LFO Depth"]
+    LfoDepth,
+    /// Speeds up or slows down the LFO, by Envelope Depth Octaves
+    #[name = "LFO Rate"]
+    LfoRate,
+    /// Narrows or widens the band, by Envelope Depth Octaves
+    Resonance,
+}
+
+/// Selects what the envelope and the onset detector listen to
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+enum DetectorSource {
+    /// The input that is being filtered
+    Main,
+    /// The sidechain input, e.g. a drum bus or a clean DI
+    Sidechain,
+    /// The sum of the main input's channels
+    Mid,
+    /// The difference of the main input's channels
+    Side,
+}
+
+/// Selects how the envelope follower measures the input level
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+enum DetectorMode {
+    Peak,
+    #[name = "RMS"]
+    Rms,
+}
+
+/// Selects which filter the modulated band is run through
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+enum FilterMode {
+    /// Resonant state-variable filter, retuned every sample
+    #[name = "State Variable"]
+    StateVariable,
+    /// Windowed-sinc FIR band-pass, a new kernel every 128 samples, delays the signal by half the filter length
+    #[name = "Linear Phase"]
+    LinearPhase,
+    /// Same magnitude response as Linear Phase, but converted to minimum phase so it adds no latency
+    #[name = "Minimum Phase"]
+    MinimumPhase,
+}
+
+/// Window applied to the Linear Phase filter's kernel
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+enum WindowFunction {
+    Hamming,
+    Hann,
+    Blackman,
+    #[name = "Blackman-Harris"]
+    BlackmanHarris,
+    /// Uses the Kaiser Beta parameter
+    Kaiser,
+}
+
+/// Factor the filters are oversampled by, keeps band edges above the original Nyquist frequency from aliasing
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+enum Oversampling {
+    Off,
+    #[name = "2x"]
+    TwoTimes,
+    #[name = "4x"]
+    FourTimes,
+    #[name = "8x"]
+    EightTimes,
+}
+
+impl Oversampling {
+    /// Number of 2x stages that make up the factor
+    fn num_stages(&self) -> usize {
+        match self {
+            Oversampling::Off => 0,
+            Oversampling::TwoTimes => 1,
+            Oversampling::FourTimes => 2,
+            Oversampling::EightTimes => 3,
+        }
+    }
+}
+
+/// Waveform of the LFO
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+enum LfoShape {
+    Sine,
+    Triangle,
+    #[name = "Saw Up"]
+    SawUp,
+    #[name = "Saw Down"]
+    SawDown,
+    /// Uses the LFO Pulse Width parameter
+    Square,
+    #[name = "Pedal Rock"]
+    PedalRock,
+    #[name = "Smooth Random"]
+    SmoothRandom,
+}
+
+/// Selects what sets the rate of the LFO
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+enum LfoSync {
+    /// LFO Frequency in Hz
+    Free,
+    /// One cycle per LFO Division at the host tempo, in phase with the song position
+    Tempo,
+}
+
+/// Note value one LFO cycle lasts in the Tempo sync mode
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+enum NoteDivision {
+    #[name = "1/1"]
+    Whole,
+    #[name = "1/2"]
+    Half,
+    #[name = "1/4"]
+    Quarter,
+    #[name = "1/8"]
+    Eighth,
+    #[name = "1/16"]
+    Sixteenth,
+    #[name = "1/32"]
+    ThirtySecond,
+}
+
+/// Lengthens or shortens the LFO Division
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+enum DivisionType {
+    Straight,
+    /// One and a half times as long
+    Dotted,
+    /// Three in the time of two
+    Triplet,
+}
+
+impl NoteDivision {
+    /// Length of the note in quarter notes, which is what hosts count song positions in
+    fn beats(&self, division_type: DivisionType) -> f64 {
+        let beats = match self {
+            NoteDivision::Whole => 4.0,
+            NoteDivision::Half => 2.0,
+            NoteDivision::Quarter => 1.0,
+            NoteDivision::Eighth => 0.5,
+            NoteDivision::Sixteenth => 0.25,
+            NoteDivision::ThirtySecond => 0.125,
+        };
+        match division_type {
+            DivisionType::Straight => beats,
+            DivisionType::Dotted => beats * 1.5,
+            DivisionType::Triplet => beats * 2.0 / 3.0,
+        }
+    }
+}
+
+/// Selects what moves the band
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+enum ModulationSource {
+    /// The expression pedal alone, from heel to toe over the LFO sweep
+    Manual,
+    #[name = "LFO"]
+    Lfo,
+    /// The envelope alone, sweeping the band itself whatever the Envelope Target and with or without Use Onset Detection
+    Envelope,
+    /// The LFO and the envelope, with the pedal moving the middle of the LFO sweep
+    Blend,
+}
+
+/// Selects the unit the sweep depth is given in
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+enum SweepMode {
+    /// LFO Intensity is an offset in Hz, moved the way the Modulation Mapping says
+    Hz,
+    /// Sweep Depth is a number of octaves, the band is multiplied up and down around its center like on a pedal
+    Octaves,
+}
+
+/// Selects how the LFO and envelope move the band in the Hz sweep mode
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+enum ModulationMapping {
+    /// Same offset in Hz at every frequency, stops dead at the ends of the range
+    Linear,
+    /// Same ratio up and down, reaching the same top as Linear but never 0 Hz
+    Exponential,
+    /// Linear, but easing into the ends of the range
+    #[name = "Soft Clamp"]
+    SoftClamp,
+}
+
+/// Selects how the pass band is specified
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+enum BandMode {
+    /// Lower and upper edge of the band
+    #[name = "Low / High"]
+    Edges,
+    /// Center frequency and resonance (Q) of the band
+    #[name = "Center / Q"]
+    CenterQ,
+}
+
+/// Selects which wah pedal circuit is emulated
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+enum PedalModel {
+    /// Generic band-pass between the low and high frequencies, the filter is chosen by ```FilterMode```
+    #[name = "Band-pass"]
+    Bandpass,
+    #[name = "Cry Baby"]
+    CryBaby,
+    #[name = "Vox"]
+    Vox,
+}
+
 #[derive(Params)]
 struct WahwahParams {
     #[persist = "editor-state"]
@@ -32,16 +370,50 @@ struct WahwahParams {
     /// gain parameter is stored as linear gain while the values are displayed in decibels.
     #[id = "gain"]
     pub gain: FloatParam,
-    #[id = "attack_rate"]
-    pub attack_rate: FloatParam,
-    #[id = "decay_rate"]
-    pub decay_rate: FloatParam,
+    #[id = "attack_time"]
+    pub attack_time: FloatParam,
+    #[id = "hold_time"]
+    pub hold_time: FloatParam,
+    #[id = "decay_time"]
+    pub decay_time: FloatParam,
+    #[id = "sustain_level"]
+    pub sustain_level: FloatParam,
+    #[id = "release_time"]
+    pub release_time: FloatParam,
+    #[id = "attack_curve"]
+    pub attack_curve: EnumParam<EnvelopeCurve>,
+    #[id = "decay_curve"]
+    pub decay_curve: EnumParam<EnvelopeCurve>,
+    #[id = "release_curve"]
+    pub release_curve: EnumParam<EnvelopeCurve>,
+    #[id = "envelope_depth"]
+    pub envelope_depth: FloatParam,
+    #[id = "envelope_depth_octaves"]
+    pub envelope_depth_octaves: FloatParam,
+    #[id = "envelope_target"]
+    pub envelope_target: EnumParam<EnvelopeTarget>,
     #[id = "onset_threshold"]
     pub onset_threshold: FloatParam,
     #[id = "reset_threshold"]
     pub reset_threshold: FloatParam,
     #[id = "use_onset_detection"]
     pub use_onset_detection: BoolParam,
+    #[id = "envelope_mode"]
+    pub envelope_mode: EnumParam<EnvelopeMode>,
+    #[id = "detector_source"]
+    pub detector_source: EnumParam<DetectorSource>,
+    #[id = "onset_method"]
+    pub onset_method: EnumParam<OnsetMethod>,
+    #[id = "detector"]
+    pub detector: EnumParam<DetectorMode>,
+    #[id = "follower_attack"]
+    pub follower_attack: FloatParam,
+    #[id = "follower_release"]
+    pub follower_release: FloatParam,
+    #[id = "follower_sensitivity"]
+    pub follower_sensitivity: FloatParam,
+    #[id = "follower_gain"]
+    pub follower_gain: FloatParam,
     #[id = "lfo_freq"]
     pub lfo_freq: FloatParam,
     #[id = "lfo_intensity"]
@@ -50,6 +422,60 @@ struct WahwahParams {
     pub base_low_filter: FloatParam,
     #[id = "base_high_filter"]
     pub base_high_filter: FloatParam,
+    #[id = "band_mode"]
+    pub band_mode: EnumParam<BandMode>,
+    #[id = "center_freq"]
+    pub center_freq: FloatParam,
+    #[id = "resonance"]
+    pub resonance: FloatParam,
+    #[id = "filter_mode"]
+    pub filter_mode: EnumParam<FilterMode>,
+    #[id = "kernel_crossfade"]
+    pub kernel_crossfade: IntParam,
+    #[id = "filter_length"]
+    pub filter_length: IntParam,
+    #[id = "window"]
+    pub window: EnumParam<WindowFunction>,
+    #[id = "kaiser_beta"]
+    pub kaiser_beta: FloatParam,
+    #[id = "pedal_model"]
+    pub pedal_model: EnumParam<PedalModel>,
+    #[id = "oversampling"]
+    pub oversampling: EnumParam<Oversampling>,
+    #[id = "modulation_mapping"]
+    pub modulation_mapping: EnumParam<ModulationMapping>,
+    #[id = "lfo_sync"]
+    pub lfo_sync: EnumParam<LfoSync>,
+    #[id = "lfo_division"]
+    pub lfo_division: EnumParam<NoteDivision>,
+    #[id = "lfo_division_type"]
+    pub lfo_division_type: EnumParam<DivisionType>,
+    #[id = "lfo_retrigger"]
+    pub lfo_retrigger: BoolParam,
+    #[id = "lfo_start_phase"]
+    pub lfo_start_phase: FloatParam,
+    #[id = "stereo_phase"]
+    pub stereo_phase: FloatParam,
+    #[id = "lfo_shape"]
+    pub lfo_shape: EnumParam<LfoShape>,
+    #[id = "pulse_width"]
+    pub pulse_width: FloatParam,
+    #[id = "sweep_mode"]
+    pub sweep_mode: EnumParam<SweepMode>,
+    #[id = "sweep_depth"]
+    pub sweep_depth: FloatParam,
+    #[id = "modulation_source"]
+    pub modulation_source: EnumParam<ModulationSource>,
+    #[id = "pedal_position"]
+    pub pedal_position: FloatParam,
+    #[id = "pedal_cc"]
+    pub pedal_cc: IntParam,
+    #[id = "pedal_smoothing"]
+    pub pedal_smoothing: FloatParam,
+    #[id = "pedal_min"]
+    pub pedal_min: FloatParam,
+    #[id = "pedal_max"]
+    pub pedal_max: FloatParam,
 
 }
 
@@ -57,9 +483,33 @@ impl Default for Wahwah {
     fn default() -> Self {
         Self {
             params: Arc::new(WahwahParams::default()),
-            envelope: Envelope::new(0.001, 0.0001, 0.0, 0.05),
-            lfo: LFO::new(4.0, 44100),
-            previous_samples_list: Vec::new(),  // Initially empty
+            envelope: Envelope::new(44100.0),
+            onset_detector: OnsetDetector::new(44100.0),
+            follower_list: Vec::new(),
+            lfo_list: Vec::new(),
+            fir_list: Vec::new(),  // Initially empty
+            svf_list: Vec::new(),
+            pedal_list: Vec::new(),
+            oversampler_list: Vec::new(),
+            oversampled: Vec::new(),
+            oversampling_stages: 0,
+            minimum_phase: MinimumPhase::new(MAX_MINIMUM_PHASE_LENGTH),
+            latency: 0,
+            lfo_values: Vec::new(),
+            stereo_offsets: Vec::new(),
+            stereo_offset: 0.0,
+            env_values: Vec::new(),
+            onsets: Vec::new(),
+            sidechain: Vec::new(),
+            detector_input: Vec::new(),
+            expression_pedal: ExpressionPedal::new(44100.0),
+            pedal_position: 0.5,
+            pedal_events: Vec::new(),
+            pedal_values: Vec::new(),
+            kernel: vec![0.0; MAX_FILTER_LENGTH],
+            kernel_designs: Vec::new(),
+            tempo: None,
+            song_position: None,
             sample_rate: 44100.0,
         }
     }
@@ -80,15 +530,99 @@ impl Default for WahwahParams {
                     max: (1.0),
                 },
             ),
-            attack_rate: FloatParam::new(
-                "Envelope Attack Rate",
-                0.001,
-                FloatRange::Linear { min: (0.0001), max: (0.1) },
+            // Time the envelope takes to rise to its peak, in milliseconds
+            attack_time: FloatParam::new(
+                "Envelope Attack",
+                20.0,
+                FloatRange::Skewed {
+                    min: (0.0),
+                    max: (2000.0),
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_unit(" ms")
+            .with_value_to_string(formatters::v2s_f32_rounded(1)),
+            // Time the envelope stays at its peak before decaying, in milliseconds
+            hold_time: FloatParam::new(
+                "Envelope Hold",
+                0.0,
+                FloatRange::Linear { min: (0.0), max: (500.0) },
+            )
+            .with_unit(" ms")
+            .with_value_to_string(formatters::v2s_f32_rounded(0)),
+            // Time the envelope takes to fall from its peak to the sustain level, in milliseconds
+            decay_time: FloatParam::new(
+                "Envelope Decay",
+                50.0,
+                FloatRange::Skewed {
+                    min: (0.0),
+                    max: (5000.0),
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_unit(" ms")
+            .with_value_to_string(formatters::v2s_f32_rounded(1)),
+            // Level the envelope stays at until the input falls to the Reset Threshold
+            sustain_level: FloatParam::new(
+                "Envelope Sustain",
+                0.0,
+                FloatRange::Linear { min: (0.0), max: (1.0) },
+            )
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            // Time the envelope takes to fall back to zero once the note ends, in milliseconds
+            release_time: FloatParam::new(
+                "Envelope Release",
+                100.0,
+                FloatRange::Skewed {
+                    min: (0.0),
+                    max: (5000.0),
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_unit(" ms")
+            .with_value_to_string(formatters::v2s_f32_rounded(1)),
+            attack_curve: EnumParam::new(
+                "Envelope Attack Curve",
+                EnvelopeCurve::Linear,
             ),
-            decay_rate: FloatParam::new(
-                "Envelope Decay Rate",
-                0.0005,
-                FloatRange::Linear { min: (0.0001), max: (0.01) },
+            decay_curve: EnumParam::new(
+                "Envelope Decay Curve",
+                EnvelopeCurve::Linear,
+            ),
+            release_curve: EnumParam::new(
+                "Envelope Release Curve",
+                EnvelopeCurve::Linear,
+            ),
+            // How far the envelope moves its target at its peak in the Hz sweep mode, negative values move it down
+            envelope_depth: FloatParam::new(
+                "Envelope Depth",
+                500.0,
+                FloatRange::SymmetricalSkewed {
+                    min: (-4000.0),
+                    max: (4000.0),
+                    factor: FloatRange::skew_factor(-1.0),
+                    center: (0.0),
+                },
+            )
+            .with_value_to_string(formatters::v2s_f32_hz_then_khz(0))
+            .with_string_to_value(formatters::s2v_f32_hz_then_khz()),
+            // How far the envelope moves its target at its peak in the Octaves sweep mode, and the LFO rate and
+            // resonance in either mode, as envelope_depth_in_hz says
+            envelope_depth_octaves: FloatParam::new(
+                "Envelope Depth Octaves",
+                1.0,
+                FloatRange::Linear {
+                    min: (-4.0),
+                    max: (4.0),
+                },
+            )
+            .with_unit(" oct")
+            .with_value_to_string(formatters::v2s_f32_rounded(2)),
+            envelope_target: EnumParam::new(
+                "Envelope Target",
+                EnvelopeTarget::LfoDepth,
             ),
             /// Amplitude of triggering the envelope
             onset_threshold: FloatParam::new(
@@ -111,6 +645,68 @@ impl Default for WahwahParams {
                 "Use Onset Detection",
                 false,
             ),
+            envelope_mode: EnumParam::new(
+                "Envelope Mode",
+                EnvelopeMode::Onset,
+            ),
+            detector_source: EnumParam::new(
+                "Detector Source",
+                DetectorSource::Main,
+            ),
+            onset_method: EnumParam::new(
+                "Onset Method",
+                OnsetMethod::Level,
+            ),
+            detector: EnumParam::new(
+                "Follower Detector",
+                DetectorMode::Peak,
+            ),
+            // Time the follower takes to rise towards a louder level, in milliseconds
+            follower_attack: FloatParam::new(
+                "Follower Attack",
+                10.0,
+                FloatRange::Skewed {
+                    min: (0.1),
+                    max: (100.0),
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_unit(" ms")
+            .with_value_to_string(formatters::v2s_f32_rounded(1)),
+            // Time the follower takes to fall back towards a quieter level, in milliseconds
+            follower_release: FloatParam::new(
+                "Follower Release",
+                150.0,
+                FloatRange::Skewed {
+                    min: (1.0),
+                    max: (2000.0),
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_unit(" ms")
+            .with_value_to_string(formatters::v2s_f32_rounded(0)),
+            // Gain in dB applied before the follower's detector
+            follower_sensitivity: FloatParam::new(
+                "Follower Sensitivity",
+                12.0,
+                FloatRange::Linear {
+                    min: (-24.0),
+                    max: (36.0),
+                },
+            )
+            .with_unit(" dB")
+            .with_value_to_string(formatters::v2s_f32_rounded(1)),
+            // Gain in dB applied to the follower's output, which then stops at full depth
+            follower_gain: FloatParam::new(
+                "Follower Gain",
+                0.0,
+                FloatRange::Linear {
+                    min: (-24.0),
+                    max: (24.0),
+                },
+            )
+            .with_unit(" dB")
+            .with_value_to_string(formatters::v2s_f32_rounded(1)),
             lfo_freq: FloatParam::new(
                 "LFO Frequency",
                 4.0,
@@ -120,32 +716,717 @@ impl Default for WahwahParams {
                 },
             ),
             lfo_intensity: FloatParam::new(
-            "LFO Intensity",
-         100.0,
-           FloatRange::Linear {
+                "LFO Intensity",
+                100.0,
+                FloatRange::Skewed {
                     min: (0.0),
-                    max: (4000.0)
-                }
-            ),
-            /// Lower bound of the band-pass filter
+                    max: (4000.0),
+                    factor: FloatRange::skew_factor(-1.0),
+                },
+            )
+            .with_value_to_string(formatters::v2s_f32_hz_then_khz(0))
+            .with_string_to_value(formatters::s2v_f32_hz_then_khz()),
+            // Lower bound of the band-pass filter, skewed so each octave gets a similar share of the slider
             base_low_filter: FloatParam::new(
                 "Bandpass Low Frequency",
                 100.0,
-                FloatRange::Linear {
-                    min: (0.0),
+                FloatRange::Skewed {
+                    min: (20.0),
                     max: (9600.0),
+                    factor: FloatRange::skew_factor(-2.0),
                 },
-            ),
-            /// Upper bound of the high-pass filter
+            )
+            .with_value_to_string(formatters::v2s_f32_hz_then_khz(0))
+            .with_string_to_value(formatters::s2v_f32_hz_then_khz()),
+            // Upper bound of the band-pass filter
             base_high_filter: FloatParam::new(
                 "Bandpass High Frequency",
                 3000.0,
+                FloatRange::Skewed {
+                    min: (20.0),
+                    max: (9600.0),
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_value_to_string(formatters::v2s_f32_hz_then_khz(0))
+            .with_string_to_value(formatters::s2v_f32_hz_then_khz()),
+            band_mode: EnumParam::new(
+                "Band Mode",
+                BandMode::Edges,
+            ),
+            // Center of the band-pass filter when using the Center / Q band mode
+            center_freq: FloatParam::new(
+                "Center Frequency",
+                800.0,
+                FloatRange::Skewed {
+                    min: (20.0),
+                    max: (9600.0),
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_value_to_string(formatters::v2s_f32_hz_then_khz(0))
+            .with_string_to_value(formatters::s2v_f32_hz_then_khz()),
+            // Q of the band-pass filter when using the Center / Q band mode, higher values give a narrower, more vocal peak
+            resonance: FloatParam::new(
+                "Resonance",
+                4.0,
+                FloatRange::Skewed {
+                    min: (0.5),
+                    max: (20.0),
+                    factor: FloatRange::skew_factor(-1.0),
+                },
+            ),
+            filter_mode: EnumParam::new(
+                "Filter Mode",
+                FilterMode::StateVariable,
+            ),
+            // Number of samples over which the Linear Phase filter fades from one kernel to the next
+            kernel_crossfade: IntParam::new(
+                "Kernel Crossfade",
+                128,
+                IntRange::Linear {
+                    min: 0,
+                    max: 4096,
+                },
+            )
+            .with_unit(" samples"),
+            // Number of taps of the Linear Phase filter, even values are rounded up to keep the kernel symmetric
+            filter_length: IntParam::new(
+                "Filter Length",
+                101,
+                IntRange::Linear {
+                    min: 15,
+                    max: MAX_FILTER_LENGTH as i32,
+                },
+            )
+            .with_unit(" taps"),
+            window: EnumParam::new(
+                "Window",
+                WindowFunction::Hamming,
+            ),
+            kaiser_beta: FloatParam::new(
+                "Kaiser Beta",
+                8.6,
                 FloatRange::Linear {
                     min: (0.0),
-                    max: (9600.0),
+                    max: (20.0),
                 },
             ),
+            pedal_model: EnumParam::new(
+                "Pedal Model",
+                PedalModel::Bandpass,
+            ),
+            oversampling: EnumParam::new(
+                "Oversampling",
+                Oversampling::Off,
+            ),
+            modulation_mapping: EnumParam::new(
+                "Modulation Mapping",
+                ModulationMapping::Linear,
+            ),
+            lfo_sync: EnumParam::new(
+                "LFO Sync",
+                LfoSync::Free,
+            ),
+            lfo_division: EnumParam::new(
+                "LFO Division",
+                NoteDivision::Quarter,
+            ),
+            lfo_division_type: EnumParam::new(
+                "LFO Division Type",
+                DivisionType::Straight,
+            ),
+            lfo_retrigger: BoolParam::new(
+                "LFO Retrigger",
+                false,
+            ),
+            // Point of the cycle a retriggered LFO restarts from, in degrees
+            lfo_start_phase: FloatParam::new(
+                "LFO Start Phase",
+                0.0,
+                FloatRange::Linear {
+                    min: (0.0),
+                    max: (360.0),
+                },
+            )
+            .with_unit("°")
+            .with_value_to_string(formatters::v2s_f32_rounded(0)),
+            // How far the right channel's LFO trails the left one's, in degrees
+            stereo_phase: FloatParam::new(
+                "Stereo Phase",
+                0.0,
+                FloatRange::Linear {
+                    min: (0.0),
+                    max: (180.0),
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_unit("°")
+            .with_value_to_string(formatters::v2s_f32_rounded(0)),
+            lfo_shape: EnumParam::new(
+                "LFO Shape",
+                LfoShape::Sine,
+            ),
+            // Fraction of each cycle the Square shape spends high
+            pulse_width: FloatParam::new(
+                "LFO Pulse Width",
+                0.5,
+                FloatRange::Linear {
+                    min: (0.05),
+                    max: (0.95),
+                },
+            )
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            sweep_mode: EnumParam::new(
+                "Sweep Mode",
+                SweepMode::Hz,
+            ),
+            // Depth of the LFO sweep in the Octaves sweep mode, in octaves either side of the band
+            sweep_depth: FloatParam::new(
+                "Sweep Depth",
+                1.0,
+                FloatRange::Linear {
+                    min: (0.0),
+                    max: (4.0),
+                },
+            )
+            .with_unit(" oct")
+            .with_value_to_string(formatters::v2s_f32_rounded(2)),
+            modulation_source: EnumParam::new(
+                "Mode",
+                ModulationSource::Blend,
+            ),
+            // Position of the expression pedal from heel to toe, moved by the pedal's CC as well
+            pedal_position: FloatParam::new(
+                "Pedal Position",
+                0.5,
+                FloatRange::Linear {
+                    min: (0.0),
+                    max: (1.0),
+                },
+            )
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            // Expression pedals usually send CC 11 (expression) or CC 4 (foot controller)
+            pedal_cc: IntParam::new(
+                "Pedal CC",
+                11,
+                IntRange::Linear {
+                    min: 0,
+                    max: 127,
+                },
+            ),
+            // Time the pedal position takes to follow a new CC value, in milliseconds
+            pedal_smoothing: FloatParam::new(
+                "Pedal Smoothing",
+                20.0,
+                FloatRange::Linear {
+                    min: (0.0),
+                    max: (200.0),
+                },
+            )
+            .with_unit(" ms")
+            .with_value_to_string(formatters::v2s_f32_rounded(0)),
+            // CC value the pedal sends at the heel
+            pedal_min: FloatParam::new(
+                "Pedal Min",
+                0.0,
+                FloatRange::Linear {
+                    min: (0.0),
+                    max: (1.0),
+                },
+            )
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            // CC value the pedal sends at the toe
+            pedal_max: FloatParam::new(
+                "Pedal Max",
+                1.0,
+                FloatRange::Linear {
+                    min: (0.0),
+                    max: (1.0),
+                },
+            )
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+        }
+    }
+}
+
+impl WahwahParams {
+    /// Return what the envelope moves
+    /// Without the LFO there is no depth or rate for the envelope to change, so in Envelope mode it moves the band itself
+    fn envelope_target(&self) -> EnvelopeTarget {
+        match self.modulation_source.value() {
+            ModulationSource::Envelope => EnvelopeTarget::FilterCenter,
+            _ => self.envelope_target.value(),
+        }
+    }
+
+    /// Return whether the envelope moves its target by Envelope Depth in Hz, rather than by Envelope Depth Octaves
+    fn envelope_depth_in_hz(&self) -> bool {
+        self.sweep_mode.value() == SweepMode::Hz
+            && matches!(self.envelope_target(), EnvelopeTarget::FilterCenter | EnvelopeTarget::LfoDepth)
+    }
+}
+
+impl Wahwah {
+    /// Number of taps of the FIR kernels, even lengths are rounded up so the linear-phase kernel stays symmetric
+    fn num_taps(&self) -> usize {
+        let max_taps = match self.params.filter_mode.value() {
+            FilterMode::MinimumPhase => MAX_MINIMUM_PHASE_LENGTH,
+            _ => MAX_FILTER_LENGTH,
+        };
+        (self.params.filter_length.value() as usize | 1).min(max_taps)
+    }
+
+    /// Delay the current settings add to the signal, in samples
+    /// The oversampling filters and the linear-phase kernel delay the signal, the kernel by its center tap at the
+    /// oversampled rate. The 4x and 8x stages have fractional delays, so the total is rounded
+    fn filter_latency(&self) -> u32 {
+        let num_stages = self.params.oversampling.value().num_stages();
+        let kernel_latency = match (self.params.pedal_model.value(), self.params.filter_mode.value()) {
+            (PedalModel::Bandpass, FilterMode::LinearPhase) => ((self.num_taps() - 1) / 2) as f32,
+            _ => 0.0,
+        };
+        let oversampling_latency = self.oversampler_list.first().map_or(0.0, |oversampler| oversampler.latency(num_stages));
+        (oversampling_latency + kernel_latency / (1 << num_stages) as f32).round() as u32
+    }
+
+    /// Queues a pedal CC ```value``` for the sample at ```timing``` in the next block
+    /// A later CC at the same sample, or one that no longer fits, replaces the last one, so the pedal always ends
+    /// up where it was last sent
+    fn queue_pedal_event(&mut self, timing: u32, value: f32) {
+        let full = self.pedal_events.len() == self.pedal_events.capacity();
+        match self.pedal_events.last_mut() {
+            Some(last) if full || last.0 == timing => *last = (timing, value),
+            _ => self.pedal_events.push((timing, value)),
+        }
+    }
+
+    /// Retunes the filters for ```num_stages``` 2x oversampling stages, and clears everything that ran at the old rate
+    fn set_oversampling_stages(&mut self, num_stages: usize) {
+        self.oversampling_stages = num_stages;
+        let filter_rate = (self.sample_rate * (1 << num_stages) as f64) as f32;
+        for svf in self.svf_list.iter_mut() {
+            *svf = StateVariableFilter::new(filter_rate);
+        }
+        for pedal in self.pedal_list.iter_mut() {
+            *pedal = WahPedal::new(wah_pedal::CRY_BABY, filter_rate);
+        }
+        for fir in self.fir_list.iter_mut() {
+            fir.reset();
+        }
+        for oversampler in self.oversampler_list.iter_mut() {
+            oversampler.reset();
+        }
+    }
+
+    /// Allocates the filters and scratch buffers for ```num_channels``` channels and blocks of up to ```max_buffer_size```
+    /// samples, so nothing has to be allocated while processing
+    fn prepare(&mut self, num_channels: usize, sample_rate: f64, max_buffer_size: usize) {
+        self.sample_rate = sample_rate;
+        self.fir_list.clear();
+        self.svf_list.clear();
+        self.pedal_list.clear();
+        self.oversampler_list.clear();
+        self.lfo_list.clear();
+        self.follower_list.clear();
+        self.envelope = Envelope::new(sample_rate as f32);
+        self.onset_detector = OnsetDetector::new(sample_rate as f32);
+        for _ in 0..num_channels {
+            self.follower_list.push(EnvelopeFollower::new(sample_rate as f32));
+            self.lfo_list.push(LFO::new(self.params.lfo_freq.value(), sample_rate as usize));
+            self.fir_list.push(FirFilter::new(MAX_FILTER_LENGTH, self.params.kernel_crossfade.value() as usize));
+            self.svf_list.push(StateVariableFilter::new(self.sample_rate as f32));
+            self.pedal_list.push(WahPedal::new(wah_pedal::CRY_BABY, self.sample_rate as f32));
+            self.oversampler_list.push(Oversampler::new(max_buffer_size));
+        }
+        self.lfo_values = vec![0.0; max_buffer_size];
+        self.stereo_offsets = vec![0.0; max_buffer_size];
+        self.stereo_offset = 0.0;
+        self.env_values = vec![vec![0.0; max_buffer_size]; num_channels];
+        self.onsets = vec![false; max_buffer_size];
+        self.kernel_designs = vec![None; num_channels];
+        self.sidechain = vec![vec![0.0; max_buffer_size]; num_channels];
+        self.detector_input = vec![vec![0.0; max_buffer_size]; num_channels];
+        self.expression_pedal = ExpressionPedal::new(sample_rate as f32);
+        self.pedal_position = self.params.pedal_position.value();
+        self.expression_pedal.set_position(self.pedal_position);
+        self.expression_pedal.reset();
+        // Room for one CC event per sample, more than any pedal sends
+        self.pedal_events = Vec::with_capacity(max_buffer_size);
+        self.pedal_values = vec![0.0; max_buffer_size];
+        self.oversampled = vec![0.0; max_buffer_size << oversampler::MAX_STAGES];
+        self.set_oversampling_stages(self.params.oversampling.value().num_stages());
+    }
+
+    /// Filters every channel of a block in place
+    /// Each parameter is first stored,
+    /// Then in State Variable mode, or with a pedal model, the filter is retuned and run sample by sample,
+    /// while in Linear Phase and Minimum Phase mode a FIR filter kernel is generated for every ```KERNEL_UPDATE_INTERVAL``` samples.
+    /// The channels may not be longer than the ```max_buffer_size``` given to ```prepare```
+    fn process_channels(&mut self, block_samples: &mut [&mut [f32]]) {
+        let gain = self.params.gain.smoothed.next();
+        let onset_threshold = self.params.onset_threshold.smoothed.next();
+        let reset_threshold = self.params.reset_threshold.smoothed.next();
+
+        let lfo_freq = self.params.lfo_freq.smoothed.next();
+        let lfo_intensity = self.params.lfo_intensity.smoothed.next();
+        let base_f_low = self.params.base_low_filter.smoothed.next();
+        let base_f_high = self.params.base_high_filter.smoothed.next();
+        let band_mode = self.params.band_mode.value();
+        let base_center_freq = self.params.center_freq.smoothed.next();
+        let resonance = self.params.resonance.smoothed.next();
+        let filter_mode = self.params.filter_mode.value();
+        let pedal_model = self.params.pedal_model.value();
+        let modulation_mapping = self.params.modulation_mapping.value();
+        let sweep_mode = self.params.sweep_mode.value();
+        let sweep_depth = self.params.sweep_depth.smoothed.next();
+        let kernel_crossfade = self.params.kernel_crossfade.value() as usize;
+        let num_taps = self.num_taps();
+        let window = match self.params.window.value() {
+            WindowFunction::Hamming => Window::Hamming,
+            WindowFunction::Hann => Window::Hann,
+            WindowFunction::Blackman => Window::Blackman,
+            WindowFunction::BlackmanHarris => Window::BlackmanHarris,
+            WindowFunction::Kaiser => Window::Kaiser(self.params.kaiser_beta.value() as f64),
+        };
+
+        let envelope_depth = self.params.envelope_depth.smoothed.next();
+        let envelope_depth_octaves = self.params.envelope_depth_octaves.smoothed.next();
+        let modulation_source = self.params.modulation_source.value();
+        let envelope_target = self.params.envelope_target();
+        let use_onset_detection = self.params.use_onset_detection.value() || modulation_source == ModulationSource::Envelope;
+        let lfo_on = matches!(modulation_source, ModulationSource::Lfo | ModulationSource::Blend);
+        let envelope_on = matches!(modulation_source, ModulationSource::Envelope | ModulationSource::Blend);
+        let pedal_on = matches!(modulation_source, ModulationSource::Manual | ModulationSource::Blend);
+        self.envelope.set_times(
+            self.params.attack_time.value(),
+            self.params.hold_time.value(),
+            self.params.decay_time.value(),
+            self.params.release_time.value(),
+        );
+        self.envelope.set_curves(
+            self.params.attack_curve.value().curve(),
+            self.params.decay_curve.value().curve(),
+            self.params.release_curve.value().curve(),
+        );
+        self.envelope.set_sustain(self.params.sustain_level.smoothed.next());
+        self.envelope.set_thresholds(onset_threshold, reset_threshold);
+        let envelope_mode = self.params.envelope_mode.value();
+        // The level method is the envelope's own threshold, the others hand the envelope the detector's onsets
+        let onset_method = match self.params.onset_method.value() {
+            OnsetMethod::Level => None,
+            OnsetMethod::SpectralFlux => Some(Method::SpectralFlux),
+            OnsetMethod::HighFrequencyContent => Some(Method::HighFrequencyContent),
+        };
+        if let Some(method) = onset_method {
+            self.onset_detector.set_method(method);
+        }
+        let detector = match self.params.detector.value() {
+            DetectorMode::Peak => Detector::Peak,
+            DetectorMode::Rms => Detector::Rms,
+        };
+        for follower in self.follower_list.iter_mut() {
+            follower.set_params(
+                detector,
+                self.params.follower_attack.value(),
+                self.params.follower_release.value(),
+                util::db_to_gain(self.params.follower_sensitivity.value()),
+                util::db_to_gain(self.params.follower_gain.value()),
+            );
+        }
+        // Without a tempo from the host the synced LFO keeps running at LFO Frequency
+        let lfo_freq = match (self.params.lfo_sync.value(), self.tempo) {
+            (LfoSync::Tempo, Some(tempo)) => {
+                let cycle_beats = self.params.lfo_division.value().beats(self.params.lfo_division_type.value());
+                if let Some(song_position) = self.song_position {
+                    let phase = (song_position / cycle_beats).rem_euclid(1.0) as f32;
+                    for (channel_index, lfo) in self.lfo_list.iter_mut().enumerate() {
+                        lfo.set_phase(phase - channel_index as f32 * self.stereo_offset);
+                    }
+                }
+                (tempo / 60.0 / cycle_beats) as f32
+            }
+            _ => lfo_freq,
+        };
+        let lfo_shape = match self.params.lfo_shape.value() {
+            LfoShape::Sine => Shape::Sine,
+            LfoShape::Triangle => Shape::Triangle,
+            LfoShape::SawUp => Shape::SawUp,
+            LfoShape::SawDown => Shape::SawDown,
+            LfoShape::Square => Shape::Square(self.params.pulse_width.value()),
+            LfoShape::PedalRock => Shape::PedalRock,
+            LfoShape::SmoothRandom => Shape::SmoothRandom,
+        };
+        for lfo in self.lfo_list.iter_mut() {
+            lfo.set_freq(lfo_freq);
+            lfo.set_shape(lfo_shape);
+        }
+
+        let num_stages = self.params.oversampling.value().num_stages();
+        if num_stages != self.oversampling_stages {
+            self.set_oversampling_stages(num_stages);
+        }
+        let factor = 1 << num_stages;
+        // The filters run at the oversampled rate
+        let sample_rate = self.sample_rate * factor as f64;
+        let num_samples = block_samples.first().map_or(0, |channel_samples| channel_samples.len());
+
+        // Mid and side are the same for every channel, a mono input has no side
+        let num_channels = block_samples.len();
+        let detector_source = self.params.detector_source.value();
+        for (channel_index, detector_samples) in self.detector_input[..num_channels].iter_mut().enumerate() {
+            let detector_samples = &mut detector_samples[..num_samples];
+            match detector_source {
+                DetectorSource::Main => detector_samples.copy_from_slice(block_samples[channel_index]),
+                DetectorSource::Sidechain => detector_samples.copy_from_slice(&self.sidechain[channel_index][..num_samples]),
+                DetectorSource::Mid | DetectorSource::Side => {
+                    let sign = if detector_source == DetectorSource::Mid { 1.0 } else { -1.0 };
+                    let left = &block_samples[0];
+                    let right = &block_samples[num_channels.min(2) - 1];
+                    for ((detector_sample, left), right) in detector_samples.iter_mut().zip(left.iter()).zip(right.iter()) {
+                        *detector_sample = 0.5 * (left + sign * right);
+                    }
+                }
+            }
+        }
+        let detector_input = &self.detector_input[..num_channels];
+
+        // The envelope runs sample by sample ahead of the filters, so its timing does not depend on the block size
+        // and an onset can restart the LFO at the sample it happens on
+        let onsets = &mut self.onsets[..num_samples];
+        onsets.fill(false);
+        match (use_onset_detection, envelope_mode) {
+            (false, _) => {
+                for env_values in self.env_values.iter_mut() {
+                    env_values[..num_samples].fill(0.0);
+                }
+            }
+            // One envelope for all channels, triggered by the loudest of them or by the onsets in their mix
+            (true, EnvelopeMode::Onset) => {
+                for i in 0..num_samples {
+                    // The envelope holds the peaks of this level, so a note ringing through zero doesn't end it
+                    let level = detector_input.iter().fold(0.0, |level: f32, channel_samples| level.max(channel_samples[i].abs()));
+                    let env_value = match onset_method {
+                        None => self.envelope.process_one_sample(&level),
+                        Some(_) => {
+                            let mix = detector_input.iter().map(|channel_samples| channel_samples[i]).sum::<f32>() / num_channels as f32;
+                            let onset = self.onset_detector.process_one_sample(mix);
+                            self.envelope.process_onset(onset, &level)
+                        }
+                    };
+                    onsets[i] = self.envelope.triggered();
+                    for env_values in self.env_values.iter_mut() {
+                        env_values[i] = env_value;
+                    }
+                }
+            }
+            (true, EnvelopeMode::Follower) => {
+                let channels = detector_input.iter().zip(self.env_values.iter_mut()).zip(self.follower_list.iter_mut());
+                for ((channel_samples, env_values), follower) in channels {
+                    for (sample, env_value) in channel_samples[..num_samples].iter().zip(env_values.iter_mut()) {
+                        *env_value = follower.process_one_sample(sample);
+                    }
+                }
+            }
+        }
+        let onsets = &*onsets;
+
+        // Moving the Pedal Position parameter moves the pedal, and so does its CC, whichever came last
+        self.expression_pedal.set_params(
+            self.params.pedal_smoothing.value(),
+            self.params.pedal_min.value(),
+            self.params.pedal_max.value(),
+        );
+        let pedal_position = self.params.pedal_position.value();
+        if pedal_position != self.pedal_position {
+            self.pedal_position = pedal_position;
+            self.expression_pedal.set_position(pedal_position);
+        }
+        let mut pedal_events = self.pedal_events.iter().peekable();
+        for (i, pedal_value) in self.pedal_values[..num_samples].iter_mut().enumerate() {
+            while let Some(&(_, value)) = pedal_events.next_if(|&&(timing, _)| timing as usize <= i) {
+                self.expression_pedal.set_cc_value(value);
+            }
+            *pedal_value = self.expression_pedal.process_one_sample();
+        }
+        self.pedal_events.clear();
+        let pedal_values = &self.pedal_values[..num_samples];
+
+        let retrigger = self.params.lfo_retrigger.value();
+        let start_phase = self.params.lfo_start_phase.value() / 360.0;
+        let rate_modulated = envelope_target == EnvelopeTarget::LfoRate && envelope_on;
+        // The first channel's envelope sets the rate of every LFO, so they all run at the same speed
+        let rate_env_values = &self.env_values[0][..num_samples];
+
+        // Each channel is a fixed fraction of a cycle behind the one before it, so with two channels
+        // the right LFO trails the left by the stereo phase. The offset lives in the LFOs' own phases and is
+        // only moved sample by sample as the Stereo Phase glides, so the LFOs never jump
+        let stereo_offsets = &mut self.stereo_offsets[..num_samples];
+        self.params.stereo_phase.smoothed.next_block(stereo_offsets, num_samples);
+        stereo_offsets.iter_mut().for_each(|offset| *offset /= 360.0);
+        let stereo_offsets = &*stereo_offsets;
+        let stereo_moving = stereo_offsets.iter().any(|&offset| offset != self.stereo_offset);
+
+        let mut channel_index = 0;
+        for channel_samples in block_samples.iter_mut() {
+            let env_values = &self.env_values[channel_index][..num_samples];
+            let lfo_values = &mut self.lfo_values[..num_samples];
+            let lfo = &mut self.lfo_list[channel_index];
+            let retriggered = retrigger && onsets.contains(&true);
+            if retriggered || rate_modulated || (stereo_moving && channel_index > 0) {
+                let mut stereo_offset = self.stereo_offset;
+                let modulation = onsets.iter().zip(rate_env_values).zip(stereo_offsets);
+                for (lfo_value, ((&onset, &env_value), &offset)) in lfo_values.iter_mut().zip(modulation) {
+                    if channel_index > 0 && offset != stereo_offset {
+                        lfo.set_phase(lfo.phase() - channel_index as f32 * (offset - stereo_offset));
+                        stereo_offset = offset;
+                    }
+                    if retriggered && onset {
+                        lfo.reset_phase(start_phase - channel_index as f32 * offset);
+                    }
+                    if rate_modulated {
+                        lfo.set_freq(lfo_freq * f32::powf(2.0, env_value * envelope_depth_octaves));
+                    }
+                    lfo.get_block(std::slice::from_mut(lfo_value));
+                }
+            } else {
+                lfo.get_block(lfo_values);
+            }
+            // The LFO keeps running while it is not used, so it does not jump when it comes back
+            // Heel to toe moves the band over the same range as the LFO's sweep
+            for (lfo_value, &pedal_value) in lfo_values.iter_mut().zip(pedal_values) {
+                let lfo_part = if lfo_on { *lfo_value } else { 0.0 };
+                let pedal_part = if pedal_on { 2.0 * pedal_value - 1.0 } else { 0.0 };
+                *lfo_value = lfo_part + pedal_part;
+            }
+            let lfo_values = &*lfo_values;
+            // Deliberately limited by the host's rate, not the oversampled one the filters run at. The downsampler
+            // removes everything above the host's Nyquist frequency, so a band swept up there would only go silent.
+            // Oversampling still keeps the filters' shape below the limit from being squeezed and stops aliasing
+            let max_freq = modulation::MAX_FREQ_RATIO * self.sample_rate as f32;
+            let modulate = |freq: f32, lfo_value: f32, mod_depth: f32| match (sweep_mode, modulation_mapping) {
+                (SweepMode::Octaves, _) => modulate_octaves(freq, lfo_value, mod_depth),
+                (SweepMode::Hz, ModulationMapping::Linear) => modulate_linear(freq, lfo_value, mod_depth),
+                (SweepMode::Hz, ModulationMapping::Exponential) => modulate_exponential(freq, lfo_value, mod_depth),
+                (SweepMode::Hz, ModulationMapping::SoftClamp) => modulate_soft_clamp(freq, lfo_value, mod_depth, max_freq),
+            };
+            // Returns the modulated center frequency and Q of the band for one LFO and envelope value
+            // The edges are always brought back into a valid band, whatever the settings and mapping
+            let modulated_band = |lfo_value: f32, env_value: f32| {
+                let env_value = if envelope_on { env_value } else { 0.0 };
+                // In Hz or in octaves, depending on the sweep mode
+                let (lfo_depth, env_depth) = match sweep_mode {
+                    SweepMode::Hz => (lfo_intensity, envelope_depth),
+                    SweepMode::Octaves => (sweep_depth, envelope_depth_octaves),
+                };
+                let mod_depth = match envelope_target {
+                    EnvelopeTarget::LfoDepth => (lfo_depth + env_value * env_depth).max(0.0),
+                    _ => lfo_depth,
+                };
+                // The envelope moves the band before the LFO sweeps it
+                let shift = |freq: f32| match (envelope_target, sweep_mode) {
+                    (EnvelopeTarget::FilterCenter, SweepMode::Hz) => modulate_linear(freq, env_value, env_depth),
+                    (EnvelopeTarget::FilterCenter, SweepMode::Octaves) => modulate_octaves(freq, env_value, env_depth),
+                    _ => freq,
+                };
+                let (mod_f_low, mod_f_high) = match band_mode {
+                    BandMode::Edges => (
+                        modulate(shift(base_f_low), lfo_value, mod_depth),
+                        modulate(shift(base_f_high), lfo_value, mod_depth),
+                    ),
+                    BandMode::CenterQ => center_q_to_edges(modulate(shift(base_center_freq), lfo_value, mod_depth), resonance),
+                };
+                let (mod_f_low, mod_f_high) = limit_band(mod_f_low, mod_f_high, max_freq);
+                let (mod_center_freq, mod_q) = edges_to_center_q(mod_f_low, mod_f_high);
+                if envelope_target != EnvelopeTarget::Resonance {
+                    return (mod_center_freq, mod_q);
+                }
+                let (mod_f_low, mod_f_high) =
+                    center_q_to_edges(mod_center_freq, mod_q * f32::powf(2.0, env_value * envelope_depth_octaves));
+                let (mod_f_low, mod_f_high) = limit_band(mod_f_low, mod_f_high, max_freq);
+                edges_to_center_q(mod_f_low, mod_f_high)
+            };
+
+            // Everything between the up and down sampling runs at the oversampled rate
+            let oversampled = &mut self.oversampled[..num_samples * factor];
+            let oversampler = &mut self.oversampler_list[channel_index];
+            oversampled[..num_samples].copy_from_slice(channel_samples);
+            oversampler.upsample(oversampled, num_stages);
+
+            match (pedal_model, filter_mode) {
+                (PedalModel::Bandpass, FilterMode::StateVariable) => {
+                    // Retune the filter on every sample so the sweep does not depend on the block size
+                    let svf = &mut self.svf_list[channel_index];
+                    for (samples, (&lfo_value, &env_value)) in oversampled.chunks_mut(factor).zip(lfo_values.iter().zip(env_values)) {
+                        let (mod_center_freq, mod_q) = modulated_band(lfo_value, env_value);
+                        svf.set_params(mod_center_freq, mod_q);
+                        for sample in samples.iter_mut() {
+                            *sample = svf.process_one_sample(sample) * gain;
+                        }
+                    }
+                }
+                (PedalModel::CryBaby | PedalModel::Vox, _) => {
+                    // The circuit models are driven by the center of the modulated band
+                    let pedal = &mut self.pedal_list[channel_index];
+                    pedal.set_voicing(if pedal_model == PedalModel::CryBaby { wah_pedal::CRY_BABY } else { wah_pedal::VOX });
+                    for (samples, (&lfo_value, &env_value)) in oversampled.chunks_mut(factor).zip(lfo_values.iter().zip(env_values)) {
+                        let (mod_center_freq, _) = modulated_band(lfo_value, env_value);
+                        pedal.set_center_freq(mod_center_freq);
+                        for sample in samples.iter_mut() {
+                            *sample = pedal.process_one_sample(sample) * gain;
+                        }
+                    }
+                }
+                (PedalModel::Bandpass, FilterMode::LinearPhase | FilterMode::MinimumPhase) => {
+                    let fir = &mut self.fir_list[channel_index];
+                    // Keep the fade the same length in time
+                    fir.set_crossfade_length(kernel_crossfade * factor);
+                    // A new kernel every few samples follows the LFO through the block, the crossfade hides the steps
+                    let modulation = lfo_values.chunks(KERNEL_UPDATE_INTERVAL).zip(env_values.chunks(KERNEL_UPDATE_INTERVAL));
+                    for (samples, (lfo_values, env_values)) in oversampled.chunks_mut(KERNEL_UPDATE_INTERVAL * factor).zip(modulation) {
+                        let (mod_center_freq, mod_q) = modulated_band(lfo_values[0], env_values[0]);
+                        let (mod_f_low, mod_f_high) = center_q_to_edges(mod_center_freq, mod_q);
+                        let design = KernelDesign {
+                            f_low: mod_f_low,
+                            f_high: mod_f_high,
+                            num_taps,
+                            window,
+                            filter_mode,
+                            filter_rate: sample_rate,
+                        };
+                        // A Minimum Phase kernel is kept until the band has moved far enough to hear
+                        let last_design = &mut self.kernel_designs[channel_index];
+                        if !last_design.is_some_and(|last_design| last_design.close_to(&design)) {
+                            let taps = &mut self.kernel[..num_taps];
+                            bandpass_fir(taps, mod_f_low as f64, mod_f_high as f64, sample_rate, window);
+                            if filter_mode == FilterMode::MinimumPhase {
+                                self.minimum_phase.convert(taps);
+                            }
+                            fir.set_kernel(taps);
+                            *last_design = Some(design);
+                        }
+                        fir.process_block(samples);
+                    }
+
+                    for sample in oversampled.iter_mut() {
+                        *sample *= gain;
+                    }
+                }
+            }
+
+            oversampler.downsample(oversampled, num_stages);
+            channel_samples.copy_from_slice(&oversampled[..num_samples]);
+            channel_index += 1;
         }
+        self.stereo_offset = stereo_offsets.last().copied().unwrap_or(self.stereo_offset);
     }
 }
 
@@ -163,17 +1444,22 @@ impl Plugin for Wahwah {
         main_input_channels: NonZeroU32::new(2),
         main_output_channels: NonZeroU32::new(2),
 
-        aux_input_ports: &[],
+        // A stereo sidechain the envelope and the onset detector can listen to instead of the main input
+        aux_input_ports: &[new_nonzero_u32(2)],
         aux_output_ports: &[],
 
         // Individual ports and the layout as a whole can be named here. By default these names
         // are generated as needed. This layout will be called 'Stereo', while a layout with
         // only one input and output channel would be called 'Mono'.
-        names: PortNames::const_default(),
+        names: PortNames {
+            aux_inputs: &["Sidechain"],
+            ..PortNames::const_default()
+        },
     }];
 
 
-    const MIDI_INPUT: MidiConfig = MidiConfig::None;
+    // Expression pedals send CCs
+    const MIDI_INPUT: MidiConfig = MidiConfig::MidiCCs;
     const MIDI_OUTPUT: MidiConfig = MidiConfig::None;
 
     const SAMPLE_ACCURATE_AUTOMATION: bool = true;
@@ -191,7 +1477,7 @@ impl Plugin for Wahwah {
         self.params.clone()
     }
 
-    /// Generates appropriate sized 2D vector for previous_samples_list to be used by the FIR filter
+    /// Generates one FIR filter, state-variable filter and wah circuit per channel
     fn initialize(
         &mut self,
         _audio_io_layout: &AudioIOLayout,
@@ -202,19 +1488,44 @@ impl Plugin for Wahwah {
         // The `reset()` function is always called right after this function. You can remove this
         // function if you do not need it.
         let num_channels = _audio_io_layout.main_input_channels;
-        self.sample_rate = _buffer_config.sample_rate as f64;
-        let num_taps = 101;  // n   ber of taps in your FIR filter
-        for _ in 0..num_channels.unwrap().into(){
-            let mut new_vec = Vec::new();
-            new_vec.resize(num_taps - 1, 0.0);
-            self.previous_samples_list.push(new_vec);
-        }
+        self.prepare(
+            num_channels.unwrap().get() as usize,
+            _buffer_config.sample_rate as f64,
+            _buffer_config.max_buffer_size as usize,
+        );
+        self.latency = self.filter_latency();
+        _context.set_latency_samples(self.latency);
         true
     }
 
     fn reset(&mut self) {
         // Reset buffers and envelopes here. This can be called from the audio thread and may not
         // allocate. You can remove this function if you do not need it.
+        // The kernels are designed again for the next block, fading in from silence as in a new filter
+        for fir in self.fir_list.iter_mut() {
+            fir.clear_kernel();
+        }
+        self.kernel_designs.fill(None);
+        for svf in self.svf_list.iter_mut() {
+            svf.reset();
+        }
+        for pedal in self.pedal_list.iter_mut() {
+            pedal.reset();
+        }
+        for oversampler in self.oversampler_list.iter_mut() {
+            oversampler.reset();
+        }
+        for follower in self.follower_list.iter_mut() {
+            follower.reset();
+        }
+        for lfo in self.lfo_list.iter_mut() {
+            lfo.reset();
+        }
+        // The LFOs start together again, the next block moves them the stereo phase apart
+        self.stereo_offset = 0.0;
+        self.envelope.reset();
+        self.onset_detector.reset();
+        self.expression_pedal.reset();
     }
 
     /// Main rendering function of the egui editor
@@ -245,11 +1556,43 @@ impl Plugin for Wahwah {
                     ui.label("Onset Detection");
                     ui.add(widgets::ParamSlider::for_param(&params.use_onset_detection, setter));
 
-                    ui.label("Attack Rate");
-                    ui.add(widgets::ParamSlider::for_param(&params.attack_rate, setter));
+                    ui.label("Mode");
+                    ui.add(widgets::ParamSlider::for_param(&params.envelope_mode, setter));
+
+                    ui.label("Detector Source");
+                    ui.add(widgets::ParamSlider::for_param(&params.detector_source, setter));
+
+                    ui.label("Onset Method");
+                    ui.add(widgets::ParamSlider::for_param(&params.onset_method, setter));
+
+                    ui.label("Attack");
+                    ui.add(widgets::ParamSlider::for_param(&params.attack_time, setter));
+                    ui.add(widgets::ParamSlider::for_param(&params.attack_curve, setter));
+
+                    ui.label("Hold");
+                    ui.add(widgets::ParamSlider::for_param(&params.hold_time, setter));
+
+                    ui.label("Decay");
+                    ui.add(widgets::ParamSlider::for_param(&params.decay_time, setter));
+                    ui.add(widgets::ParamSlider::for_param(&params.decay_curve, setter));
+
+                    ui.label("Sustain");
+                    ui.add(widgets::ParamSlider::for_param(&params.sustain_level, setter));
+
+                    ui.label("Release");
+                    ui.add(widgets::ParamSlider::for_param(&params.release_time, setter));
+                    ui.add(widgets::ParamSlider::for_param(&params.release_curve, setter));
+
+                    // Only the depth the target uses in the current sweep mode is shown
+                    ui.label("Depth");
+                    if params.envelope_depth_in_hz() {
+                        ui.add(widgets::ParamSlider::for_param(&params.envelope_depth, setter));
+                    } else {
+                        ui.add(widgets::ParamSlider::for_param(&params.envelope_depth_octaves, setter));
+                    }
 
-                    ui.label("Decay Rate");
-                    ui.add(widgets::ParamSlider::for_param(&params.decay_rate, setter));
+                    ui.label("Target");
+                    ui.add(widgets::ParamSlider::for_param(&params.envelope_target, setter));
 
                     ui.label("Onset Threshold");
                     ui.add(widgets::ParamSlider::for_param(&params.onset_threshold, setter));
@@ -257,6 +1600,21 @@ impl Plugin for Wahwah {
                     ui.label("Reset Threshold");
                     ui.add(widgets::ParamSlider::for_param(&params.reset_threshold, setter));
 
+                    ui.label("Follower Detector");
+                    ui.add(widgets::ParamSlider::for_param(&params.detector, setter));
+
+                    ui.label("Follower Attack");
+                    ui.add(widgets::ParamSlider::for_param(&params.follower_attack, setter));
+
+                    ui.label("Follower Release");
+                    ui.add(widgets::ParamSlider::for_param(&params.follower_release, setter));
+
+                    ui.label("Sensitivity");
+                    ui.add(widgets::ParamSlider::for_param(&params.follower_sensitivity, setter));
+
+                    ui.label("Follower Gain");
+                    ui.add(widgets::ParamSlider::for_param(&params.follower_gain, setter));
+
                     
                     // ui.label("Onset Detection");
                     // ui.add(widgets::ParamSlider::for_param(&params.use_onset_detection, setter));
@@ -273,6 +1631,59 @@ impl Plugin for Wahwah {
                     ui.label("Intensity");
                     ui.add(widgets::ParamSlider::for_param(&params.lfo_intensity, setter));
 
+                    ui.label("Sync");
+                    ui.add(widgets::ParamSlider::for_param(&params.lfo_sync, setter));
+
+                    ui.label("Division");
+                    ui.add(widgets::ParamSlider::for_param(&params.lfo_division, setter));
+
+                    ui.label("Division Type");
+                    ui.add(widgets::ParamSlider::for_param(&params.lfo_division_type, setter));
+
+                    ui.label("Retrigger");
+                    ui.add(widgets::ParamSlider::for_param(&params.lfo_retrigger, setter));
+
+                    ui.label("Start Phase");
+                    ui.add(widgets::ParamSlider::for_param(&params.lfo_start_phase, setter));
+
+                    ui.label("Stereo Phase");
+                    ui.add(widgets::ParamSlider::for_param(&params.stereo_phase, setter));
+
+                    ui.label("Shape");
+                    ui.add(widgets::ParamSlider::for_param(&params.lfo_shape, setter));
+
+                    ui.label("Pulse Width");
+                    ui.add(widgets::ParamSlider::for_param(&params.pulse_width, setter));
+
+                    ui.label("Sweep Mode");
+                    ui.add(widgets::ParamSlider::for_param(&params.sweep_mode, setter));
+
+                    ui.label("Sweep Depth");
+                    ui.add(widgets::ParamSlider::for_param(&params.sweep_depth, setter));
+
+                    ui.separator();
+
+                    ui.with_layout(egui::Layout::top_down(egui::Align::Center).with_cross_align(egui::Align::Center), |ui| {
+                        ui.label("Pedal");
+                    });
+                    ui.label("Mode");
+                    ui.add(widgets::ParamSlider::for_param(&params.modulation_source, setter));
+
+                    ui.label("Position");
+                    ui.add(widgets::ParamSlider::for_param(&params.pedal_position, setter));
+
+                    ui.label("CC");
+                    ui.add(widgets::ParamSlider::for_param(&params.pedal_cc, setter));
+
+                    ui.label("Smoothing");
+                    ui.add(widgets::ParamSlider::for_param(&params.pedal_smoothing, setter));
+
+                    ui.label("Min");
+                    ui.add(widgets::ParamSlider::for_param(&params.pedal_min, setter));
+
+                    ui.label("Max");
+                    ui.add(widgets::ParamSlider::for_param(&params.pedal_max, setter));
+
                     ui.separator();
 
                     ui.with_layout(egui::Layout::top_down(egui::Align::Center).with_cross_align(egui::Align::Center), |ui| {
@@ -284,6 +1695,36 @@ impl Plugin for Wahwah {
                     ui.label("High Frequency");
                     ui.add(widgets::ParamSlider::for_param(&params.base_high_filter, setter));
 
+                    ui.label("Band Mode");
+                    ui.add(widgets::ParamSlider::for_param(&params.band_mode, setter));
+
+                    ui.label("Center Frequency");
+                    ui.add(widgets::ParamSlider::for_param(&params.center_freq, setter));
+
+                    ui.label("Resonance");
+                    ui.add(widgets::ParamSlider::for_param(&params.resonance, setter));
+
+                    ui.label("Pedal Model");
+                    ui.add(widgets::ParamSlider::for_param(&params.pedal_model, setter));
+
+                    ui.label("Mode");
+                    ui.add(widgets::ParamSlider::for_param(&params.filter_mode, setter));
+
+                    ui.label("Filter Length");
+                    ui.add(widgets::ParamSlider::for_param(&params.filter_length, setter));
+
+                    ui.label("Window");
+                    ui.add(widgets::ParamSlider::for_param(&params.window, setter));
+
+                    ui.label("Kaiser Beta");
+                    ui.add(widgets::ParamSlider::for_param(&params.kaiser_beta, setter));
+
+                    ui.label("Oversampling");
+                    ui.add(widgets::ParamSlider::for_param(&params.oversampling, setter));
+
+                    ui.label("Modulation Mapping");
+                    ui.add(widgets::ParamSlider::for_param(&params.modulation_mapping, setter));
+
                     // This is a simple naieve version of a parameter slider that's not aware of how
                     // the parameters work
                     // ui.add(
@@ -323,134 +1764,700 @@ impl Plugin for Wahwah {
     }
 
     /// Processes each block of incoming audio and writes output in-place
-    /// Each parameter is first stored,
-    /// Then the FIR filter kernel is generated, and the audio is filtered blockwise.
+    /// Reports any change in latency to the host, the filtering itself is done by ```process_channels```
     fn process(
         &mut self,
         buffer: &mut Buffer,
-        _aux: &mut AuxiliaryBuffers,
+        aux: &mut AuxiliaryBuffers,
         _context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
-        let gain = self.params.gain.smoothed.next();
-        let attack_rate = self.params.attack_rate.smoothed.next();
-        let decay_rate = self.params.decay_rate.smoothed.next();
-        let onset_threshold = self.params.onset_threshold.smoothed.next();
-        let reset_threshold = self.params.reset_threshold.smoothed.next();
-        let use_onset_detection = self.params.use_onset_detection.value();
+        // Let the host compensate for the linear-phase kernel's delay whenever it changes
+        let latency = self.filter_latency();
+        if latency != self.latency {
+            self.latency = latency;
+            _context.set_latency_samples(latency);
+        }
+        let transport = _context.transport();
+        self.tempo = transport.tempo;
+        self.song_position = if transport.playing { transport.pos_beats() } else { None };
+        // A mono sidechain feeds every channel, an unconnected one is silence
+        let sidechain = aux.inputs.first().map_or(&[][..], |input| input.as_slice_immutable());
+        for (channel_index, sidechain_samples) in self.sidechain.iter_mut().enumerate() {
+            match sidechain.get(channel_index).or(sidechain.first()) {
+                Some(input) => sidechain_samples[..input.len()].copy_from_slice(input),
+                None => sidechain_samples.fill(0.0),
+            }
+        }
 
-        let lfo_freq = self.params.lfo_freq.smoothed.next();
-        let lfo_intensity = self.params.lfo_intensity.smoothed.next();
-        let base_f_low = self.params.base_low_filter.smoothed.next();
-        let base_f_high = self.params.base_high_filter.smoothed.next();
+        // Only the pedal's CC is kept, at most one per sample so the queue never has to grow
+        while let Some(event) = _context.next_event() {
+            if let NoteEvent::MidiCC { timing, cc, value, .. } = event {
+                if cc as i32 == self.params.pedal_cc.value() {
+                    self.queue_pedal_event(timing, value);
+                }
+            }
+        }
+
+        self.process_channels(buffer.as_slice());
+        ProcessStatus::Normal
+    }
+}
 
-        self.envelope.set_params(attack_rate, decay_rate, onset_threshold, reset_threshold);
-        self.lfo.set_freq(lfo_freq);
+impl ClapPlugin for Wahwah {
+    const CLAP_ID: &'static str = "com.your-domain.WahWah";
+    const CLAP_DESCRIPTION: Option<&'static str> = Some("A short description of your plugin");
+    const CLAP_MANUAL_URL: Option<&'static str> = Some(Self::URL);
+    const CLAP_SUPPORT_URL: Option<&'static str> = None;
 
-        let num_taps = 101;
-        let sample_rate = self.sample_rate;
-        let block_samples = buffer.as_slice();
-        let mut lfo_values = vec![0.0; block_samples.len()];
-        self.lfo.get_block(&mut lfo_values);
+    // Don't forget to change these features
+    const CLAP_FEATURES: &'static [ClapFeature] = &[ClapFeature::AudioEffect, ClapFeature::Stereo];
+}
 
-        let mut channel_index = 0;
-        for channel_samples in block_samples{
-            let mut env_value = 0.0;
-            if use_onset_detection {
-                let mean_sample: f32 = (*channel_samples).iter().sum::<f32>() / (*channel_samples).len() as f32;
-                env_value = self.envelope.process_one_sample(&mean_sample) * 500.0;
+impl Vst3Plugin for Wahwah {
+    const VST3_CLASS_ID: [u8; 16] = *b"ase_final_wahwah";
+
+    // And also don't forget to change these categories
+    const VST3_SUBCATEGORIES: &'static [Vst3SubCategory] =
+        &[Vst3SubCategory::Fx, Vst3SubCategory::Dynamics];
+}
+
+nih_export_clap!(Wahwah);
+nih_export_vst3!(Wahwah);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_BUFFER_SIZE: usize = 1024;
+
+    /// Fills ```block``` with white noise between -1.0 and 1.0
+    fn noise(seed: &mut u32, block: &mut [f32]) {
+        for sample in block.iter_mut() {
+            *seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            *sample = (*seed >> 8) as f32 / (1 << 23) as f32 - 1.0;
+        }
+    }
+
+    /// Creates a stereo plugin at 44.1 kHz with ```params```, prepared for blocks of up to ```max_buffer_size``` samples
+    fn prepared(params: WahwahParams, max_buffer_size: usize) -> Wahwah {
+        let mut wahwah = Wahwah {
+            params: Arc::new(params),
+            ..Wahwah::default()
+        };
+        wahwah.prepare(2, 44100.0, max_buffer_size);
+        wahwah
+    }
+
+    /// Return ```num_samples``` samples of a half-scale sine at ```freq``` Hz, starting ```start``` samples in
+    fn sine(freq: f32, start: usize, num_samples: usize) -> Vec<f32> {
+        (start..start + num_samples)
+            .map(|i| 0.5 * f32::sin(i as f32 * 2.0 * std::f32::consts::PI * freq / 44100.0))
+            .collect()
+    }
+
+    /// Runs ```block``` through both channels and return the left channel's output
+    fn process_mono(wahwah: &mut Wahwah, block: &[f32]) -> Vec<f32> {
+        let mut left = block.to_vec();
+        let mut right = block.to_vec();
+        wahwah.process_channels(&mut [left.as_mut_slice(), right.as_mut_slice()]);
+        left
+    }
+
+    /// Return the RMS level of ```samples```
+    fn rms(samples: &[f32]) -> f32 {
+        f32::sqrt(samples.iter().map(|sample| sample * sample).sum::<f32>() / samples.len() as f32)
+    }
+
+    /// Return decaying noise bursts, like picked notes, 3000 samples apart
+    fn plucks() -> Vec<f32> {
+        let mut input = vec![0.0; 12288];
+        noise(&mut 1, &mut input);
+        for (i, sample) in input.iter_mut().enumerate() {
+            *sample *= f32::exp(-((i % 3000) as f32) / 300.0);
+        }
+        input
+    }
+
+    /// Runs ```plucks``` through ```wahwah``` in blocks of ```block_size``` samples and return the left channel's
+    /// envelope and output
+    fn process_plucks(wahwah: &mut Wahwah, block_size: usize) -> (Vec<f32>, Vec<f32>) {
+        let mut envelope = Vec::new();
+        let mut output = Vec::new();
+        for block in plucks().chunks(block_size) {
+            output.extend(process_mono(wahwah, block));
+            envelope.extend_from_slice(&wahwah.env_values[0][..block.len()]);
+        }
+        (envelope, output)
+    }
+
+    /// Runs 16 blocks of ```block_size``` samples of noise through every channel and fails on any allocation
+    fn process_without_allocating(wahwah: &mut Wahwah, block_size: usize) {
+        let mut left = vec![0.0; block_size];
+        let mut right = vec![0.0; block_size];
+        let mut seed: u32 = 1;
+        for _ in 0..16 {
+            noise(&mut seed, &mut left);
+            noise(&mut seed, &mut right);
+            let mut channels = [left.as_mut_slice(), right.as_mut_slice()];
+            assert_no_alloc::assert_no_alloc(|| wahwah.process_channels(&mut channels));
+            assert!(channels.iter().all(|channel| channel.iter().all(|sample| sample.is_finite())));
+        }
+    }
+
+    #[test]
+    fn test_process_does_not_allocate() {
+        let configurations = [
+            (PedalModel::Bandpass, FilterMode::StateVariable, Oversampling::Off),
+            (PedalModel::Bandpass, FilterMode::LinearPhase, Oversampling::Off),
+            (PedalModel::Bandpass, FilterMode::MinimumPhase, Oversampling::Off),
+            (PedalModel::CryBaby, FilterMode::StateVariable, Oversampling::Off),
+            (PedalModel::Bandpass, FilterMode::StateVariable, Oversampling::EightTimes),
+            (PedalModel::Bandpass, FilterMode::LinearPhase, Oversampling::FourTimes),
+            (PedalModel::Vox, FilterMode::StateVariable, Oversampling::TwoTimes),
+        ];
+        for (pedal_model, filter_mode, oversampling) in configurations {
+            let params = WahwahParams {
+                pedal_model: EnumParam::new("Pedal Model", pedal_model),
+                filter_mode: EnumParam::new("Filter Mode", filter_mode),
+                oversampling: EnumParam::new("Oversampling", oversampling),
+                // Long enough to use the partitioned convolver
+                filter_length: IntParam::new("Filter Length", 301, IntRange::Linear { min: 15, max: MAX_FILTER_LENGTH as i32 }),
+                use_onset_detection: BoolParam::new("Use Onset Detection", true),
+                onset_method: EnumParam::new("Onset Method", OnsetMethod::SpectralFlux),
+                ..WahwahParams::default()
+            };
+            let mut wahwah = prepared(params, MAX_BUFFER_SIZE);
+            for block_size in [1, 2, 3, 17, 64, 100, 127, 128, 500, 512, 1000, MAX_BUFFER_SIZE] {
+                process_without_allocating(&mut wahwah, block_size);
             }
+        }
+    }
 
-            let mod_f_low = base_f_low + (lfo_values[channel_index] * (lfo_intensity + env_value));
-            let mod_f_high = base_f_high + (lfo_values[channel_index] * (lfo_intensity + env_value));
-            let taps = bandpass_fir(num_taps, mod_f_low as f64, mod_f_high as f64, sample_rate);
-            let filtered_block = apply_fir_filter_blockwise(&channel_samples, &taps, &mut self.previous_samples_list[channel_index]);
+    #[test]
+    fn test_minimum_phase_length_is_capped() {
+        for (filter_mode, num_taps) in [(FilterMode::LinearPhase, MAX_FILTER_LENGTH), (FilterMode::MinimumPhase, MAX_MINIMUM_PHASE_LENGTH)] {
+            let params = WahwahParams {
+                filter_mode: EnumParam::new("Filter Mode", filter_mode),
+                filter_length: IntParam::new("Filter Length", 4095, IntRange::Linear { min: 15, max: MAX_FILTER_LENGTH as i32 }),
+                ..WahwahParams::default()
+            };
+            let mut wahwah = prepared(params, 256);
+            assert_eq!(wahwah.num_taps(), num_taps);
+            process_without_allocating(&mut wahwah, 256);
+        }
+    }
 
-            for (sample, &processed) in channel_samples.iter_mut().zip(filtered_block.iter()) {
-                *sample = processed * gain;
+    #[test]
+    fn test_extreme_settings_stay_bounded() {
+        let filters = [
+            (PedalModel::Bandpass, FilterMode::StateVariable),
+            (PedalModel::Bandpass, FilterMode::LinearPhase),
+            (PedalModel::Bandpass, FilterMode::MinimumPhase),
+            (PedalModel::CryBaby, FilterMode::StateVariable),
+        ];
+        let mappings = [
+            (SweepMode::Hz, ModulationMapping::Linear),
+            (SweepMode::Hz, ModulationMapping::Exponential),
+            (SweepMode::Hz, ModulationMapping::SoftClamp),
+            (SweepMode::Octaves, ModulationMapping::Linear),
+        ];
+        // Edges at both ends of their ranges and crossed, the deepest and fastest LFO, and the envelope always triggered
+        let bands = [(0.0, 0.0, 20.0, 20.0), (9600.0, 0.0, 9600.0, 0.5), (0.0, 9600.0, 20.0, 0.5), (9600.0, 9600.0, 9600.0, 20.0)];
+        for (pedal_model, filter_mode) in filters {
+            for (sweep_mode, mapping) in mappings {
+                for band_mode in [BandMode::Edges, BandMode::CenterQ] {
+                    for (low, high, center, resonance) in bands {
+                        let params = WahwahParams {
+                            pedal_model: EnumParam::new("Pedal Model", pedal_model),
+                            filter_mode: EnumParam::new("Filter Mode", filter_mode),
+                            modulation_mapping: EnumParam::new("Modulation Mapping", mapping),
+                            sweep_mode: EnumParam::new("Sweep Mode", sweep_mode),
+                            sweep_depth: FloatParam::new("Sweep Depth", 4.0, FloatRange::Linear { min: 0.0, max: 4.0 }),
+                            band_mode: EnumParam::new("Band Mode", band_mode),
+                            base_low_filter: FloatParam::new("Bandpass Low Frequency", low, FloatRange::Linear { min: 0.0, max: 9600.0 }),
+                            base_high_filter: FloatParam::new("Bandpass High Frequency", high, FloatRange::Linear { min: 0.0, max: 9600.0 }),
+                            center_freq: FloatParam::new("Center Frequency", center, FloatRange::Linear { min: 20.0, max: 9600.0 }),
+                            resonance: FloatParam::new("Resonance", resonance, FloatRange::Linear { min: 0.5, max: 20.0 }),
+                            lfo_freq: FloatParam::new("LFO Frequency", 100.0, FloatRange::Linear { min: 0.0, max: 100.0 }),
+                            lfo_intensity: FloatParam::new("LFO Intensity", 4000.0, FloatRange::Linear { min: 0.0, max: 4000.0 }),
+                            use_onset_detection: BoolParam::new("Use Onset Detection", true),
+                            onset_threshold: FloatParam::new("Onset Threshold", 0.0, FloatRange::Linear { min: 0.0, max: 1.0 }),
+                            ..WahwahParams::default()
+                        };
+                        let mut wahwah = prepared(params, 256);
+                        let mut left = vec![0.0; 256];
+                        let mut right = vec![0.0; 256];
+                        let mut seed: u32 = 1;
+                        for _ in 0..8 {
+                            noise(&mut seed, &mut left);
+                            noise(&mut seed, &mut right);
+                            let mut channels = [left.as_mut_slice(), right.as_mut_slice()];
+                            wahwah.process_channels(&mut channels);
+                            for sample in channels.iter().flat_map(|channel| channel.iter()) {
+                                assert!(sample.is_finite());
+                                assert!(sample.abs() < 10.0);
+                            }
+                        }
+                    }
+                }
             }
-            channel_index += 1;
         }
-        ProcessStatus::Normal
     }
-}
 
-/// Generates a convolution kernel of size ```num_taps``` for an FIR bandpass filter
-/// within the frequency band between ```f_low``` and ```f_high```
-fn bandpass_fir(num_taps: usize, f_low: f64, f_high: f64, sample_rate: f64) -> Vec<f64> {
-    let mut taps = vec![0.0; num_taps];
-    let center = num_taps / 2;
-    let fl = f_low / sample_rate;
-    let fh = f_high / sample_rate;
-    for i in 0..num_taps {
-        let n = i as f64 - center as f64;
+    #[test]
+    fn test_lfo_follows_song_position() {
+        let params = WahwahParams {
+            lfo_sync: EnumParam::new("LFO Sync", LfoSync::Tempo),
+            lfo_division: EnumParam::new("LFO Division", NoteDivision::Eighth),
+            lfo_division_type: EnumParam::new("LFO Division Type", DivisionType::Dotted),
+            ..WahwahParams::default()
+        };
+        let mut wahwah = prepared(params, 441);
+
+        // A dotted eighth is 0.75 quarter notes, so at 120 BPM a cycle lasts 0.375 seconds
+        // and 1.5 quarter notes into the song the LFO is back at the start of a cycle
+        wahwah.tempo = Some(120.0);
+        wahwah.song_position = Some(1.5);
+        process_mono(&mut wahwah, &[0.0; 441]);
+        assert!(f32::abs(wahwah.lfo_list[0].phase() - 0.01 / 0.375) < 0.0001);
+
+        // Restarting playback lines the LFO up with the song position again, whatever it was doing
+        wahwah.song_position = Some(1.875);
+        process_mono(&mut wahwah, &[0.0; 441]);
+        assert!(f32::abs(wahwah.lfo_list[0].phase() - (0.5 + 0.01 / 0.375)) < 0.0001);
+
+        // Stopped, it keeps running at the tempo
+        wahwah.song_position = None;
+        process_mono(&mut wahwah, &[0.0; 441]);
+        assert!(f32::abs(wahwah.lfo_list[0].phase() - (0.5 + 0.02 / 0.375)) < 0.0001);
+    }
 
-        // Avoid division by zero in the sinc function calculation
-        if n == 0.0 {
-            taps[i] = 2.0 * (fh - fl);
-        } else {
-            taps[i] = (2.0 * fh * (f64::sin(2.0 * std::f64::consts::PI * fh * n) / (2.0 * std::f64::consts::PI * fh * n))) -
-                (2.0 * fl * (f64::sin(2.0 * std::f64::consts::PI * fl * n) / (2.0 * std::f64::consts::PI * fl * n)));
+    #[test]
+    fn test_onset_retriggers_lfo() {
+        let params = WahwahParams {
+            use_onset_detection: BoolParam::new("Use Onset Detection", true),
+            lfo_retrigger: BoolParam::new("LFO Retrigger", true),
+            lfo_start_phase: FloatParam::new("LFO Start Phase", 90.0, FloatRange::Linear { min: 0.0, max: 360.0 }),
+            ..WahwahParams::default()
+        };
+        let mut wahwah = prepared(params, 441);
+
+        // Silence lets the LFO run freely
+        for _ in 0..10 {
+            process_mono(&mut wahwah, &[0.0; 441]);
         }
+        assert!(f32::abs(wahwah.lfo_list[0].phase() - 0.4) < 0.0001);
 
-        // Apply a Hamming window to the sinc function
-        taps[i] *= 0.54 - 0.46 * f64::cos(2.0 * std::f64::consts::PI * i as f64 / (num_taps - 1) as f64);
+        // A loud block is an onset, the block's sweep starts from the start phase
+        process_mono(&mut wahwah, &[1.0; 441]);
+        assert!(f32::abs(wahwah.lfo_list[0].phase() - (0.25 + 0.04)) < 0.0001);
+        assert!(f32::abs(wahwah.lfo_list[1].phase() - (0.25 + 0.04)) < 0.0001);
     }
 
-    taps
-}
+    #[test]
+    fn test_stereo_phase() {
+        let params = WahwahParams {
+            stereo_phase: FloatParam::new("Stereo Phase", 90.0, FloatRange::Linear { min: 0.0, max: 180.0 }),
+            ..WahwahParams::default()
+        };
+        let mut wahwah = prepared(params, 441);
+        for _ in 0..10 {
+            process_mono(&mut wahwah, &[0.0; 441]);
+        }
+        // The right LFO is a quarter of a cycle behind, so over the last block it sweeps along the left one's
+        // values from a quarter cycle earlier
+        assert!(f32::abs(wahwah.lfo_list[0].phase() - 0.4) < 0.0001);
+        assert!(f32::abs(wahwah.lfo_list[1].phase() - 0.15) < 0.0001);
+        for i in 0..441 {
+            let phase = 0.11 + 4.0 * i as f32 / 44100.0;
+            assert!(f32::abs(wahwah.lfo_values[i] - f32::sin(phase * 2.0 * std::f32::consts::PI)) < 0.0001);
+        }
+    }
+
+    /// Runs ```plucks``` through the left channel and silence through the right, so the channels' followers see
+    /// different levels, in blocks of ```block_size``` samples and return the right channel's LFO
+    fn right_lfo_under_plucks(wahwah: &mut Wahwah, block_size: usize) -> Vec<f32> {
+        let mut lfo_values = Vec::new();
+        for block in plucks().chunks(block_size) {
+            let mut left = block.to_vec();
+            let mut right = vec![0.0; block.len()];
+            wahwah.process_channels(&mut [left.as_mut_slice(), right.as_mut_slice()]);
+            // The right channel is processed last, so its LFO is what is left in the buffer
+            lfo_values.extend_from_slice(&wahwah.lfo_values[..block.len()]);
+        }
+        lfo_values
+    }
+
+    /// Stereo LFOs a quarter of a cycle apart, with their rate following the envelope
+    fn rate_modulated_stereo_params() -> WahwahParams {
+        WahwahParams {
+            use_onset_detection: BoolParam::new("Use Onset Detection", true),
+            envelope_mode: EnumParam::new("Envelope Mode", EnvelopeMode::Follower),
+            envelope_target: EnumParam::new("Envelope Target", EnvelopeTarget::LfoRate),
+            stereo_phase: FloatParam::new("Stereo Phase", 90.0, FloatRange::Linear { min: 0.0, max: 180.0 }),
+            ..WahwahParams::default()
+        }
+    }
+
+    #[test]
+    fn test_stereo_lfos_do_not_depend_on_block_size() {
+        let lfo_values: Vec<Vec<f32>> = [32, 256, 2048]
+            .iter()
+            .map(|&block_size| right_lfo_under_plucks(&mut prepared(rate_modulated_stereo_params(), 2048), block_size))
+            .collect();
+        assert_eq!(lfo_values[0], lfo_values[1]);
+        assert_eq!(lfo_values[0], lfo_values[2]);
+    }
+
+    #[test]
+    fn test_rate_modulated_stereo_lfos_stay_continuous() {
+        let mut wahwah = prepared(rate_modulated_stereo_params(), 100);
+        let mut lfo_values = Vec::new();
+        for block in plucks().chunks(100) {
+            let mut left = block.to_vec();
+            let mut right = vec![0.0; block.len()];
+            wahwah.process_channels(&mut [left.as_mut_slice(), right.as_mut_slice()]);
+            lfo_values.extend_from_slice(&wahwah.lfo_values[..block.len()]);
+            // Both LFOs speed up and slow down together, so they are still a quarter of a cycle apart
+            let apart = (wahwah.lfo_list[0].phase() - wahwah.lfo_list[1].phase()).rem_euclid(1.0);
+            assert!(f32::abs(apart - 0.25) < 0.0001, "{}", apart);
+        }
+        // The envelope at most doubles the 4 Hz rate, so no step between samples, across block boundaries
+        // or not, is bigger than a sine at 8 Hz can make
+        let max_step = 2.0 * std::f32::consts::PI * 8.0 / 44100.0;
+        assert!(lfo_values.windows(2).all(|pair| f32::abs(pair[1] - pair[0]) <= max_step * 1.01));
+    }
+
+    #[test]
+    fn test_minimum_phase_kernel_kept_for_small_moves() {
+        let design = KernelDesign {
+            f_low: 500.0,
+            f_high: 1000.0,
+            num_taps: 255,
+            window: Window::Hann,
+            filter_mode: FilterMode::MinimumPhase,
+            filter_rate: 44100.0,
+        };
+        assert!(design.close_to(&KernelDesign { f_low: 502.0, f_high: 1005.0, ..design }));
+        // A quarter of a semitone is the most either edge can move
+        assert!(!design.close_to(&KernelDesign { f_low: 510.0, ..design }));
+        assert!(!design.close_to(&KernelDesign { f_high: 980.0, ..design }));
+        assert!(!design.close_to(&KernelDesign { num_taps: 257, ..design }));
+        assert!(!design.close_to(&KernelDesign { window: Window::Blackman, ..design }));
+        // Linear Phase kernels are cheap enough to follow every move
+        let linear_phase = KernelDesign { filter_mode: FilterMode::LinearPhase, ..design };
+        assert!(!linear_phase.close_to(&linear_phase));
+    }
 
-/// Uses the FIR filter kernel generated by ```bandpass_fir``` and convolves the input signal
-fn apply_fir_filter_blockwise(input: &[f32], taps: &Vec<f64>, previous_samples: &mut Vec<f32>) -> Vec<f32> {
-    let num_taps = taps.len();
-    let num_samples = input.len();
-    let mut output = vec![0.0; num_samples];
+    #[test]
+    fn test_fir_follows_lfo_through_block() {
+        // With one long block the kernel still has to move with the LFO, so the output differs from the same
+        // block with the LFO standing still
+        let run = |lfo_freq: f32| {
+            let params = WahwahParams {
+                filter_mode: EnumParam::new("Filter Mode", FilterMode::MinimumPhase),
+                lfo_freq: FloatParam::new("LFO Frequency", lfo_freq, FloatRange::Linear { min: 0.0, max: 100.0 }),
+                lfo_intensity: FloatParam::new("LFO Intensity", 2000.0, FloatRange::Linear { min: 0.0, max: 4000.0 }),
+                ..WahwahParams::default()
+            };
+            let mut wahwah = prepared(params, 1024);
+            let mut input = vec![0.0; 1024];
+            noise(&mut 1, &mut input);
+            process_mono(&mut wahwah, &input)
+        };
+        let still = run(0.0);
+        let moving = run(20.0);
+        // The first kernel is the same, later ones are not
+        assert!(still[..KERNEL_UPDATE_INTERVAL].iter().zip(&moving).all(|(a, b)| a == b));
+        assert!(still[512..].iter().zip(&moving[512..]).any(|(a, b)| f32::abs(a - b) > 0.01));
+    }
+
+    #[test]
+    fn test_follower_tracks_dynamics() {
+        let params = WahwahParams {
+            use_onset_detection: BoolParam::new("Use Onset Detection", true),
+            envelope_mode: EnumParam::new("Envelope Mode", EnvelopeMode::Follower),
+            follower_sensitivity: FloatParam::new("Follower Sensitivity", 0.0, FloatRange::Linear { min: -24.0, max: 36.0 }),
+            ..WahwahParams::default()
+        };
+        let mut wahwah = prepared(params, 441);
+        let mut block = vec![0.0; 441];
+        let mut seed: u32 = 1;
 
-    // Ensure the buffer has enough samples to cover the FIR filter requirement
-    previous_samples.resize(num_taps - 1, 0.0);
+        // Noise at -6 dB, the fast attack keeps the level near its peaks
+        for _ in 0..10 {
+            noise(&mut seed, &mut block);
+            block.iter_mut().for_each(|sample| *sample *= 0.5);
+            process_mono(&mut wahwah, &block);
+        }
+        let loud = wahwah.env_values[0][440];
+        assert!(loud > 0.3 && loud < 0.5);
 
-    // Combine previous and current samples
-    let combined_samples = previous_samples.iter().cloned().chain(input.iter().cloned()).collect::<Vec<f32>>();
+        // 100 ms after the playing stops the release has only brought it part of the way down
+        for _ in 0..10 {
+            process_mono(&mut wahwah, &[0.0; 441]);
+        }
+        let quiet = wahwah.env_values[0][440];
+        assert!(quiet > 0.3 * loud && quiet < 0.6 * loud);
+    }
+
+    #[test]
+    fn test_envelope_moves_filter_up_and_down() {
+        // A steady 2 kHz tone holds the follower at full depth, which moves a 1 kHz band
+        let level = |envelope_depth: f32| {
+            let params = WahwahParams {
+                pedal_model: EnumParam::new("Pedal Model", PedalModel::Bandpass),
+                filter_mode: EnumParam::new("Filter Mode", FilterMode::StateVariable),
+                band_mode: EnumParam::new("Band Mode", BandMode::CenterQ),
+                center_freq: FloatParam::new("Center Frequency", 1000.0, FloatRange::Linear { min: 20.0, max: 9600.0 }),
+                lfo_intensity: FloatParam::new("LFO Intensity", 0.0, FloatRange::Linear { min: 0.0, max: 4000.0 }),
+                use_onset_detection: BoolParam::new("Use Onset Detection", true),
+                envelope_mode: EnumParam::new("Envelope Mode", EnvelopeMode::Follower),
+                follower_sensitivity: FloatParam::new("Follower Sensitivity", 36.0, FloatRange::Linear { min: -24.0, max: 36.0 }),
+                envelope_target: EnumParam::new("Envelope Target", EnvelopeTarget::FilterCenter),
+                envelope_depth: FloatParam::new("Envelope Depth", envelope_depth, FloatRange::Linear { min: -4000.0, max: 4000.0 }),
+                ..WahwahParams::default()
+            };
+            let mut wahwah = prepared(params, 441);
+            let output: Vec<f32> = (0..100).flat_map(|block| process_mono(&mut wahwah, &sine(2000.0, block * 441, 441))).collect();
+            rms(&output[50 * 441..])
+        };
+        let up = level(1000.0);
+        let still = level(0.0);
+        let down = level(-500.0);
+        // Up onto the tone passes it, down away from it cuts it further than the band at rest
+        assert!(up > 0.3);
+        assert!(up > 2.0 * still);
+        assert!(still > 2.0 * down);
+    }
 
-    for i in 0..num_samples {
-        let mut acc = 0.0;
-        for j in 0..num_taps {
-            if i + num_taps <= combined_samples.len() {
-                acc += combined_samples[i + j] * taps[j] as f32;
+    #[test]
+    fn test_envelope_depth_in_hz() {
+        let cases = [
+            (SweepMode::Hz, EnvelopeTarget::FilterCenter, ModulationSource::Blend, true),
+            (SweepMode::Hz, EnvelopeTarget::LfoDepth, ModulationSource::Blend, true),
+            (SweepMode::Hz, EnvelopeTarget::LfoRate, ModulationSource::Blend, false),
+            (SweepMode::Hz, EnvelopeTarget::Resonance, ModulationSource::Blend, false),
+            (SweepMode::Octaves, EnvelopeTarget::FilterCenter, ModulationSource::Blend, false),
+            // Envelope mode always moves the band
+            (SweepMode::Hz, EnvelopeTarget::LfoRate, ModulationSource::Envelope, true),
+        ];
+        for (sweep_mode, envelope_target, modulation_source, in_hz) in cases {
+            let params = WahwahParams {
+                sweep_mode: EnumParam::new("Sweep Mode", sweep_mode),
+                envelope_target: EnumParam::new("Envelope Target", envelope_target),
+                modulation_source: EnumParam::new("Mode", modulation_source),
+                ..WahwahParams::default()
+            };
+            assert_eq!(params.envelope_depth_in_hz(), in_hz);
+        }
+    }
+
+    #[test]
+    fn test_sidechain_triggers_envelope() {
+        // Quiet main input, a loud burst on the sidechain halfway through
+        for (detector_source, triggered) in [(DetectorSource::Main, false), (DetectorSource::Sidechain, true)] {
+            let params = WahwahParams {
+                use_onset_detection: BoolParam::new("Use Onset Detection", true),
+                detector_source: EnumParam::new("Detector Source", detector_source),
+                ..WahwahParams::default()
+            };
+            let mut wahwah = prepared(params, 512);
+            let mut seed: u32 = 1;
+            let mut block = vec![0.0; 512];
+            noise(&mut seed, &mut block);
+            block.iter_mut().for_each(|sample| *sample *= 0.01);
+            for sidechain in wahwah.sidechain.iter_mut() {
+                noise(&mut seed, &mut sidechain[256..]);
             }
+            process_mono(&mut wahwah, &block);
+            assert_eq!(wahwah.onsets[..512].contains(&true), triggered);
+            assert!(!wahwah.onsets[..256].contains(&true));
         }
-        output[i] = acc;
     }
 
-    // Update previous_samples for the next block
-    if input.len() >= num_taps {
-        previous_samples.clear();
-        previous_samples.extend_from_slice(&input[input.len() - num_taps + 1..]);
-    } else {
-        // Maintain sliding window of samples
-        previous_samples.drain(0..input.len());
-        previous_samples.extend_from_slice(input);
+    #[test]
+    fn test_mid_and_side_sources() {
+        // Channels in opposite phase are all side and no mid
+        let envelope = |detector_source: DetectorSource| {
+            let params = WahwahParams {
+                use_onset_detection: BoolParam::new("Use Onset Detection", true),
+                envelope_mode: EnumParam::new("Envelope Mode", EnvelopeMode::Follower),
+                detector_source: EnumParam::new("Detector Source", detector_source),
+                ..WahwahParams::default()
+            };
+            let mut wahwah = prepared(params, 4410);
+            let mut left = vec![0.0; 4410];
+            noise(&mut 1, &mut left);
+            let mut right: Vec<f32> = left.iter().map(|sample| -sample).collect();
+            wahwah.process_channels(&mut [left.as_mut_slice(), right.as_mut_slice()]);
+            (wahwah.env_values[0][4409], wahwah.env_values[1][4409])
+        };
+        assert_eq!(envelope(DetectorSource::Mid), (0.0, 0.0));
+        let (left, right) = envelope(DetectorSource::Side);
+        assert!(left > 0.1);
+        assert_eq!(left, right);
     }
 
-    output
-}
+    #[test]
+    fn test_pedal_cc_moves_band() {
+        // A 2 kHz tone against a 1 kHz band, which the pedal sweeps up to 2 kHz at the toe
+        let params = WahwahParams {
+            pedal_model: EnumParam::new("Pedal Model", PedalModel::Bandpass),
+            filter_mode: EnumParam::new("Filter Mode", FilterMode::StateVariable),
+            band_mode: EnumParam::new("Band Mode", BandMode::CenterQ),
+            center_freq: FloatParam::new("Center Frequency", 1000.0, FloatRange::Linear { min: 20.0, max: 9600.0 }),
+            lfo_intensity: FloatParam::new("LFO Intensity", 1000.0, FloatRange::Linear { min: 0.0, max: 4000.0 }),
+            modulation_source: EnumParam::new("Mode", ModulationSource::Manual),
+            pedal_smoothing: FloatParam::new("Pedal Smoothing", 0.0, FloatRange::Linear { min: 0.0, max: 200.0 }),
+            pedal_min: FloatParam::new("Pedal Min", 0.25, FloatRange::Linear { min: 0.0, max: 1.0 }),
+            pedal_max: FloatParam::new("Pedal Max", 0.75, FloatRange::Linear { min: 0.0, max: 1.0 }),
+            ..WahwahParams::default()
+        };
+        let mut wahwah = prepared(params, 441);
+        let mut levels = Vec::new();
+        for block in 0..100 {
+            // The CC lands in the middle of a block and takes effect at its sample, calibrated to the toe
+            if block == 50 {
+                wahwah.pedal_events.push((220, 0.8));
+            }
+            let output = process_mono(&mut wahwah, &sine(2000.0, block * 441, 441));
+            if block == 50 {
+                assert_eq!(wahwah.pedal_values[219], 0.5);
+                assert_eq!(wahwah.pedal_values[220], 1.0);
+            }
+            levels.push(rms(&output));
+        }
+        assert!(wahwah.pedal_events.is_empty());
+        assert!(levels[99] > 0.3);
+        assert!(levels[99] > 2.0 * levels[49]);
+    }
 
-impl ClapPlugin for Wahwah {
-    const CLAP_ID: &'static str = "com.your-domain.WahWah";
-    const CLAP_DESCRIPTION: Option<&'static str> = Some("A short description of your plugin");
-    const CLAP_MANUAL_URL: Option<&'static str> = Some(Self::URL);
-    const CLAP_SUPPORT_URL: Option<&'static str> = None;
+    #[test]
+    fn test_full_pedal_queue_keeps_last_value() {
+        let mut wahwah = prepared(WahwahParams::default(), 4);
+        wahwah.queue_pedal_event(0, 0.1);
+        wahwah.queue_pedal_event(0, 0.2);
+        for (timing, value) in [(1, 0.3), (2, 0.4), (3, 0.5), (3, 0.6), (3, 0.7)] {
+            wahwah.queue_pedal_event(timing, value);
+        }
+        assert_eq!(wahwah.pedal_events, [(0, 0.2), (1, 0.3), (2, 0.4), (3, 0.7)]);
+        wahwah.queue_pedal_event(4, 0.8);
+        assert_eq!(wahwah.pedal_events.capacity(), 4);
+        assert_eq!(wahwah.pedal_events.last(), Some(&(4, 0.8)));
+    }
 
-    // Don't forget to change these features
-    const CLAP_FEATURES: &'static [ClapFeature] = &[ClapFeature::AudioEffect, ClapFeature::Stereo];
-}
+    #[test]
+    fn test_envelope_mode_moves_band() {
+        // Nothing but the envelope moves the band in Envelope mode, so the plucks sound different from the band at rest
+        let output = |modulation_source: ModulationSource| {
+            let params = WahwahParams {
+                modulation_source: EnumParam::new("Mode", modulation_source),
+                ..WahwahParams::default()
+            };
+            let mut wahwah = prepared(params, 256);
+            process_plucks(&mut wahwah, 256).1
+        };
+        let moving = output(ModulationSource::Envelope);
+        let still = output(ModulationSource::Manual);
+        assert!(moving.iter().zip(&still).any(|(a, b)| f32::abs(a - b) > 0.01));
+    }
 
-impl Vst3Plugin for Wahwah {
-    const VST3_CLASS_ID: [u8; 16] = *b"ase_final_wahwah";
+    #[test]
+    fn test_spectral_onsets_trigger_envelope() {
+        for onset_method in [OnsetMethod::SpectralFlux, OnsetMethod::HighFrequencyContent] {
+            let params = WahwahParams {
+                use_onset_detection: BoolParam::new("Use Onset Detection", true),
+                onset_method: EnumParam::new("Onset Method", onset_method),
+                ..WahwahParams::default()
+            };
+            let mut wahwah = prepared(params, 256);
+            let (envelope, _) = process_plucks(&mut wahwah, 256);
+            // Each of the four full plucks starts the attack again, so the envelope turns from falling to rising
+            let starts: Vec<usize> = (2..envelope.len())
+                .filter(|&i| envelope[i] > envelope[i - 1] && envelope[i - 1] <= envelope[i - 2])
+                .collect();
+            assert_eq!(starts.len(), 4, "{:?}", starts);
+            for (pluck, start) in starts.iter().enumerate() {
+                assert!((3000 * pluck..3000 * pluck + 2048).contains(start));
+            }
+        }
+    }
 
-    // And also don't forget to change these categories
-    const VST3_SUBCATEGORIES: &'static [Vst3SubCategory] =
-        &[Vst3SubCategory::Fx, Vst3SubCategory::Dynamics];
-}
+    #[test]
+    fn test_envelope_does_not_depend_on_block_size() {
+        for envelope_mode in [EnvelopeMode::Onset, EnvelopeMode::Follower] {
+            let mut results = Vec::new();
+            for block_size in [32, 256, 2048] {
+                let params = WahwahParams {
+                    use_onset_detection: BoolParam::new("Use Onset Detection", true),
+                    envelope_mode: EnumParam::new("Envelope Mode", envelope_mode),
+                    lfo_retrigger: BoolParam::new("LFO Retrigger", true),
+                    ..WahwahParams::default()
+                };
+                let mut wahwah = prepared(params, 2048);
+                results.push(process_plucks(&mut wahwah, block_size));
+            }
 
-nih_export_clap!(Wahwah);
-nih_export_vst3!(Wahwah);
+            let (envelope, output) = &results[0];
+            // Every pluck has to move the envelope, or there is nothing to compare
+            assert!(envelope.iter().any(|&env_value| env_value > 0.5));
+            for (other_envelope, other_output) in &results[1..] {
+                assert_eq!(envelope, other_envelope);
+                for (a, b) in output.iter().zip(other_output) {
+                    assert!(f32::abs(a - b) < 0.00001);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_reset_matches_fresh_plugin() {
+        let params = || WahwahParams {
+            filter_mode: EnumParam::new("Filter Mode", FilterMode::MinimumPhase),
+            use_onset_detection: BoolParam::new("Use Onset Detection", true),
+            envelope_target: EnumParam::new("Envelope Target", EnvelopeTarget::LfoRate),
+            stereo_phase: FloatParam::new("Stereo Phase", 90.0, FloatRange::Linear { min: 0.0, max: 180.0 }),
+            lfo_shape: EnumParam::new("LFO Shape", LfoShape::SmoothRandom),
+            ..WahwahParams::default()
+        };
+        // Stopped in the middle of a pluck's release, with the LFOs and the kernels somewhere along their sweep
+        let mut wahwah = prepared(params(), 256);
+        process_plucks(&mut wahwah, 256);
+        process_mono(&mut wahwah, &plucks()[..200]);
+        wahwah.reset();
+        let after_reset = process_plucks(&mut wahwah, 256);
+        let fresh = process_plucks(&mut prepared(params(), 256), 256);
+        assert_eq!(after_reset, fresh);
+    }
+
+    #[test]
+    fn test_held_notes_trigger_once_at_any_block_size() {
+        // Two notes of a 220 Hz sine, each held for half a second and followed by a quarter second of silence
+        let mut note = sine(220.0, 0, 22050);
+        note.resize(33075, 0.0);
+        let input = note.repeat(2);
+        let mut envelopes = Vec::new();
+        for block_size in [32, 256, 2048] {
+            let params = WahwahParams {
+                use_onset_detection: BoolParam::new("Use Onset Detection", true),
+                sustain_level: FloatParam::new("Envelope Sustain", 0.5, FloatRange::Linear { min: 0.0, max: 1.0 }),
+                ..WahwahParams::default()
+            };
+            let mut wahwah = prepared(params, 2048);
+            let mut onsets = Vec::new();
+            let mut envelope = Vec::new();
+            for block in input.chunks(block_size) {
+                process_mono(&mut wahwah, block);
+                onsets.extend((0..block.len()).filter(|&i| wahwah.onsets[i]).map(|i| envelope.len() + i));
+                envelope.extend_from_slice(&wahwah.env_values[0][..block.len()]);
+            }
+            // The sine reaches the onset threshold ten samples into each note
+            assert_eq!(onsets, [10, 33085], "block size {}", block_size);
+            envelopes.push(envelope);
+        }
+        assert_eq!(envelopes[0], envelopes[1]);
+        assert_eq!(envelopes[0], envelopes[2]);
+    }
+
+}
@@ -4,7 +4,6 @@ use crate::lfo::LFO;
 
 mod lfo;
 mod envelope;
-mod ring_buffer;
 
 fn main() {
     let mut reader = hound::WavReader::open("input_instrument.wav").expect("Failed to open WAV file");
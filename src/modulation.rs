@@ -0,0 +1,96 @@
+/// Lowest frequency a modulated band edge can reach in Hz
+pub const MIN_FREQ: f32 = 20.0;
+/// Highest frequency a modulated band edge can reach, as a fraction of the host's sample rate
+pub const MAX_FREQ_RATIO: f32 = 0.45;
+/// Narrowest band the modulation can produce in Hz, keeps the low edge below the high edge
+pub const MIN_BANDWIDTH: f32 = 20.0;
+
+/// Moves ```freq``` by ```modulation * depth``` Hz
+/// Equal steps in Hz, so the band can be pushed below 0 Hz and above Nyquist and relies on ```limit_band```
+pub fn modulate_linear(freq: f32, modulation: f32, depth: f32) -> f32 {
+    freq + modulation * depth
+}
+
+/// Multiplies ```freq``` by ```modulation * octaves``` octaves, so the sweep sounds equally wide at any frequency
+pub fn modulate_octaves(freq: f32, modulation: f32, octaves: f32) -> f32 {
+    freq.max(MIN_FREQ) * f32::powf(2.0, modulation * octaves)
+}
+
+/// Moves ```freq``` by the same ratio up and down, reaching ```freq + depth``` when ```modulation``` is 1.0
+/// Equal steps in octaves, so the sweep never reaches 0 Hz however deep it is
+pub fn modulate_exponential(freq: f32, modulation: f32, depth: f32) -> f32 {
+    let freq = freq.max(MIN_FREQ);
+    freq * f32::powf((freq + depth.max(0.0)) / freq, modulation)
+}
+
+/// Moves ```freq``` by ```modulation * depth``` Hz like ```modulate_linear```, but eases into the limits
+/// instead of hitting them, so a sweep into either end slows down smoothly rather than stopping dead
+pub fn modulate_soft_clamp(freq: f32, modulation: f32, depth: f32, max_freq: f32) -> f32 {
+    let freq = freq + modulation * depth;
+    // Identity in the middle of the range, exponential approach to the limits within a knee of either end
+    let low_knee = MIN_FREQ;
+    let high_knee = 0.25 * max_freq;
+    if freq < MIN_FREQ + low_knee {
+        MIN_FREQ + low_knee * f32::exp((freq - MIN_FREQ - low_knee) / low_knee)
+    } else if freq > max_freq - high_knee {
+        max_freq - high_knee * f32::exp((max_freq - high_knee - freq) / high_knee)
+    } else {
+        freq
+    }
+}
+
+/// Puts a pair of modulated band edges back into a usable band-pass: sorted, at least ```MIN_BANDWIDTH``` wide,
+/// and within ```MIN_FREQ``` and ```max_freq```
+pub fn limit_band(f_low: f32, f_high: f32, max_freq: f32) -> (f32, f32) {
+    // NaN from a broken modulation source ends up at the bottom of the range instead of in the filter
+    let f_low = if f_low.is_nan() { MIN_FREQ } else { f_low };
+    let f_high = if f_high.is_nan() { MIN_FREQ } else { f_high };
+    let max_freq = max_freq.max(MIN_FREQ + MIN_BANDWIDTH);
+    let (f_low, f_high) = if f_low <= f_high { (f_low, f_high) } else { (f_high, f_low) };
+    let f_low = f_low.clamp(MIN_FREQ, max_freq - MIN_BANDWIDTH);
+    (f_low, f_high.clamp(f_low + MIN_BANDWIDTH, max_freq))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exponential_is_symmetric_in_octaves() {
+        let up = modulate_exponential(1000.0, 1.0, 1000.0);
+        let down = modulate_exponential(1000.0, -1.0, 1000.0);
+        assert!(f32::abs(up - 2000.0) < 0.01);
+        assert!(f32::abs(down - 500.0) < 0.01);
+        assert!(modulate_exponential(100.0, -1.0, 4000.0) > 2.0);
+    }
+
+    #[test]
+    fn test_octaves() {
+        assert!(f32::abs(modulate_octaves(440.0, 1.0, 2.0) - 1760.0) < 0.01);
+        assert!(f32::abs(modulate_octaves(440.0, -0.5, 2.0) - 220.0) < 0.01);
+        assert_eq!(modulate_octaves(440.0, 0.7, 0.0), 440.0);
+    }
+
+    #[test]
+    fn test_soft_clamp_is_smooth_and_bounded() {
+        let mut previous = modulate_soft_clamp(0.0, -1.0, 100000.0, 20000.0);
+        for i in -1000..=1000 {
+            let freq = modulate_soft_clamp(0.0, i as f32 / 1000.0, 100000.0, 20000.0);
+            assert!((MIN_FREQ..=20000.0).contains(&freq));
+            assert!(freq >= previous);
+            previous = freq;
+        }
+        assert_eq!(modulate_soft_clamp(1000.0, 0.5, 1000.0, 20000.0), 1500.0);
+    }
+
+    #[test]
+    fn test_limit_band() {
+        assert_eq!(limit_band(500.0, 2000.0, 20000.0), (500.0, 2000.0));
+        // Crossed, negative, past the top and collapsed edges
+        assert_eq!(limit_band(2000.0, 500.0, 20000.0), (500.0, 2000.0));
+        assert_eq!(limit_band(-3000.0, -1000.0, 20000.0), (MIN_FREQ, MIN_FREQ + MIN_BANDWIDTH));
+        assert_eq!(limit_band(30000.0, 50000.0, 20000.0), (20000.0 - MIN_BANDWIDTH, 20000.0));
+        assert_eq!(limit_band(1000.0, 1000.0, 20000.0), (1000.0, 1000.0 + MIN_BANDWIDTH));
+        assert_eq!(limit_band(f32::NAN, 1000.0, 20000.0), (MIN_FREQ, 1000.0));
+    }
+}
@@ -0,0 +1,239 @@
+use std::sync::Arc;
+
+use realfft::num_complex::Complex;
+use realfft::{RealFftPlanner, RealToComplex};
+
+/// Length of the analysis frame in seconds, rounded to the nearest power of two in samples
+const FRAME_TIME: f32 = 0.023;
+/// Number of hops per frame
+const OVERLAP: usize = 4;
+/// Compression applied to the magnitudes before the spectral flux, so quiet partials count as well as loud ones
+const LOG_COMPRESSION: f32 = 100.0;
+/// Number of past frames the adaptive threshold takes the median of
+const MEDIAN_FRAMES: usize = 15;
+/// The detection function has to exceed its recent median by this factor
+const THRESHOLD_RATIO: f32 = 1.5;
+/// Added to the adaptive threshold, so the noise between notes, where the median is close to zero, does not trigger
+const THRESHOLD_OFFSET: f32 = 0.01;
+/// Shortest time between two onsets in seconds, stops one pick attack from triggering twice
+const MIN_ONSET_INTERVAL: f32 = 0.05;
+
+/// Selects the onset detection function
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Method {
+    /// Sum of the rises of every bin's log magnitude, catches soft notes that change the spectrum but not the level
+    SpectralFlux,
+    /// Rise of the frequency-weighted energy, follows the noisy attack of a pick stroke and ignores sustained lows
+    HighFrequencyContent,
+}
+
+/// STFT onset detector with an adaptive median threshold
+///
+/// Every hop the last frame of input is transformed and reduced to one value of a detection function that peaks
+/// where a note starts. A peak counts as an onset if it stands out from the median of the recent frames, so the
+/// detector adapts to the level of the playing instead of relying on a fixed threshold.
+/// Onsets are reported one hop after the frame they peak in, as the peak has to be seen to fall again.
+pub struct OnsetDetector {
+    method: Method,
+    frame_size: usize,
+    hop_size: usize,
+    input: Vec<f32>,  // The last frame of input, oldest first
+    hop_position: usize,  // Number of samples of the current hop received so far
+    window: Vec<f32>,
+    fft: Arc<dyn RealToComplex<f32>>,
+    fft_input: Vec<f32>,
+    spectrum: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+    magnitudes: Vec<f32>,  // Log-compressed magnitudes of the previous frame
+    previous_hfc: f32,
+    history: Vec<f32>,  // Recent values of the detection function, oldest overwritten first
+    history_index: usize,
+    sorted: Vec<f32>,  // Scratch space for taking the median of ```history```
+    previous_values: (f32, f32),  // Detection function and threshold of the previous frame
+    before_previous_value: f32,
+    frames_since_onset: usize,
+    min_onset_frames: usize,
+}
+
+impl OnsetDetector {
+    /// Creates a spectral flux detector for input at ```sample_rate```
+    pub fn new(sample_rate: f32) -> Self {
+        let frame_size = 1 << (FRAME_TIME * sample_rate).log2().round() as usize;
+        let hop_size = frame_size / OVERLAP;
+        let fft = RealFftPlanner::<f32>::new().plan_fft_forward(frame_size);
+        let scratch = vec![Complex::new(0.0, 0.0); fft.get_scratch_len()];
+        let window = (0..frame_size)
+            .map(|i| 0.5 - 0.5 * f32::cos(2.0 * std::f32::consts::PI * i as f32 / frame_size as f32))
+            .collect();
+
+        let min_onset_frames = (MIN_ONSET_INTERVAL * sample_rate / hop_size as f32).ceil() as usize;
+        OnsetDetector {
+            method: Method::SpectralFlux,
+            frame_size,
+            hop_size,
+            input: vec![0.0; frame_size],
+            hop_position: 0,
+            window,
+            fft,
+            fft_input: vec![0.0; frame_size],
+            spectrum: vec![Complex::new(0.0, 0.0); frame_size / 2 + 1],
+            scratch,
+            magnitudes: vec![0.0; frame_size / 2 + 1],
+            previous_hfc: 0.0,
+            history: vec![0.0; MEDIAN_FRAMES],
+            history_index: 0,
+            sorted: vec![0.0; MEDIAN_FRAMES],
+            previous_values: (0.0, 0.0),
+            before_previous_value: 0.0,
+            frames_since_onset: min_onset_frames,
+            min_onset_frames,
+        }
+    }
+
+    /// Clears the input and the history of the detection function
+    pub fn reset(&mut self) {
+        self.input.fill(0.0);
+        self.hop_position = 0;
+        self.magnitudes.fill(0.0);
+        self.previous_hfc = 0.0;
+        self.history.fill(0.0);
+        self.history_index = 0;
+        self.previous_values = (0.0, 0.0);
+        self.before_previous_value = 0.0;
+        self.frames_since_onset = self.min_onset_frames;
+    }
+
+    pub fn set_method(&mut self, method: Method) {
+        self.method = method;
+    }
+
+    /// Adds a sample and return whether an onset has been detected
+    pub fn process_one_sample(&mut self, sample: f32) -> bool {
+        self.input[self.frame_size - self.hop_size + self.hop_position] = sample;
+        self.hop_position += 1;
+        if self.hop_position < self.hop_size {
+            return false;
+        }
+        self.hop_position = 0;
+
+        let value = self.detection_function();
+        self.input.copy_within(self.hop_size.., 0);
+
+        // Adaptive threshold from the frames before this one
+        self.sorted.copy_from_slice(&self.history);
+        self.sorted.sort_unstable_by(|a, b| a.total_cmp(b));
+        let threshold = THRESHOLD_RATIO * self.sorted[MEDIAN_FRAMES / 2] + THRESHOLD_OFFSET;
+        self.history[self.history_index] = value;
+        self.history_index = (self.history_index + 1) % MEDIAN_FRAMES;
+
+        // The previous frame is an onset if it peaked above its threshold
+        let (previous_value, previous_threshold) = self.previous_values;
+        let onset = previous_value > previous_threshold
+            && previous_value >= self.before_previous_value
+            && previous_value > value
+            && self.frames_since_onset >= self.min_onset_frames;
+        self.frames_since_onset = if onset { 0 } else { self.frames_since_onset + 1 };
+        self.before_previous_value = previous_value;
+        self.previous_values = (value, threshold);
+        onset
+    }
+
+    /// Transforms the current frame and return the value of the detection function for it
+    fn detection_function(&mut self) -> f32 {
+        for ((fft_input, input), window) in self.fft_input.iter_mut().zip(self.input.iter()).zip(self.window.iter()) {
+            *fft_input = input * window;
+        }
+        self.fft
+            .process_with_scratch(&mut self.fft_input, &mut self.spectrum, &mut self.scratch)
+            .expect("buffer sizes match the FFT plan");
+
+        let scale = 2.0 / self.frame_size as f32;
+        match self.method {
+            Method::SpectralFlux => {
+                let mut flux = 0.0;
+                for (bin, magnitude) in self.spectrum.iter().zip(self.magnitudes.iter_mut()) {
+                    let compressed = f32::ln(1.0 + LOG_COMPRESSION * bin.norm() * scale);
+                    flux += (compressed - *magnitude).max(0.0);
+                    *magnitude = compressed;
+                }
+                // Averaged over the bins, so the values are on the same scale as the frame size changes
+                flux / self.magnitudes.len() as f32
+            }
+            Method::HighFrequencyContent => {
+                let hfc: f32 = self.spectrum.iter().enumerate().map(|(k, bin)| k as f32 * bin.norm_sqr() * scale * scale).sum();
+                let rise = (hfc - self.previous_hfc).max(0.0);
+                self.previous_hfc = hfc;
+                rise
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Note starts in the first ten seconds of ```input_instrument.wav``` in seconds, read off the waveform by hand
+    const LABELLED_ONSETS: [f32; 29] = [
+        0.42, 0.60, 0.97, 1.53, 1.74, 1.93, 2.87, 3.07, 3.25, 3.46, 3.66, 3.86, 4.05, 4.86, 5.14, 5.33, 5.69, 6.08,
+        6.55, 6.71, 6.88, 7.23, 7.73, 7.92, 8.33, 8.48, 9.39, 9.77, 9.97,
+    ];
+    /// Detections may come this much before a label, the frame reaches a little ahead of its hop
+    const EARLY_TOLERANCE: f32 = 0.02;
+    /// Detections may come this much after a label, the frame length and the peak picking delay them
+    const LATE_TOLERANCE: f32 = 0.06;
+
+    /// Return the times of the onsets detected in the first ten seconds of ```input_instrument.wav```
+    fn detect(method: Method) -> Vec<f32> {
+        let mut reader = hound::WavReader::open(concat!(env!("CARGO_MANIFEST_DIR"), "/input_instrument.wav")).unwrap();
+        let sample_rate = reader.spec().sample_rate as f32;
+        let mut detector = OnsetDetector::new(sample_rate);
+        detector.set_method(method);
+        reader
+            .samples::<i16>()
+            .take(10 * sample_rate as usize)
+            .enumerate()
+            .filter(|(_, sample)| detector.process_one_sample(*sample.as_ref().unwrap() as f32 / 32768.0))
+            .map(|(i, _)| i as f32 / sample_rate)
+            .collect()
+    }
+
+    fn matches(label: f32, detection: f32) -> bool {
+        (label - EARLY_TOLERANCE..=label + LATE_TOLERANCE).contains(&detection)
+    }
+
+    fn assert_finds_labelled_onsets(method: Method) {
+        let detections = detect(method);
+        let found = LABELLED_ONSETS
+            .iter()
+            .filter(|label| detections.iter().any(|detection| matches(**label, *detection)))
+            .count();
+        let false_positives = detections
+            .iter()
+            .filter(|detection| !LABELLED_ONSETS.iter().any(|label| matches(*label, **detection)))
+            .count();
+        assert!(found >= LABELLED_ONSETS.len() * 9 / 10, "{:?} found {} of {} onsets", method, found, LABELLED_ONSETS.len());
+        assert!(false_positives <= 2, "{:?} detected {} false onsets", method, false_positives);
+    }
+
+    #[test]
+    fn test_spectral_flux_finds_labelled_onsets() {
+        assert_finds_labelled_onsets(Method::SpectralFlux);
+    }
+
+    #[test]
+    fn test_hfc_finds_labelled_onsets() {
+        assert_finds_labelled_onsets(Method::HighFrequencyContent);
+    }
+
+    #[test]
+    fn test_silence_and_steady_tone_do_not_trigger() {
+        let mut detector = OnsetDetector::new(44100.0);
+        assert!((0..44100).all(|_| !detector.process_one_sample(0.0)));
+        // A tone is one onset when it starts, then nothing while it holds
+        let onsets = (0..44100)
+            .filter(|i| detector.process_one_sample(0.5 * f32::sin(*i as f32 * 2.0 * std::f32::consts::PI * 440.0 / 44100.0)))
+            .count();
+        assert_eq!(onsets, 1);
+    }
+}
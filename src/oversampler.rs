@@ -0,0 +1,230 @@
+use crate::fir::Window;
+use crate::simd::{select_dot_product, DotProduct};
+
+/// Number of 2x stages needed for the largest oversampling factor, 8x
+pub const MAX_STAGES: usize = 3;
+/// Length of the first stage's half-band filter, it has to keep the whole audio band so it needs the steepest slope
+const FIRST_STAGE_TAPS: usize = 63;
+/// Length of the later stages' half-band filters, they only have to reject images far above the audio band
+const LATER_STAGE_TAPS: usize = 19;
+/// Gives roughly 80 dB of image and alias rejection
+const KAISER_BETA: f64 = 8.0;
+
+/// The last inputs of a polyphase branch, stored twice so the window is always one contiguous slice
+struct History {
+    buffer: Vec<f32>,
+    index: usize,
+}
+
+impl History {
+    fn new(length: usize) -> Self {
+        History {
+            buffer: vec![0.0; 2 * length],
+            index: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.buffer.fill(0.0);
+        self.index = 0;
+    }
+
+    /// Adds a sample and return the window of the last inputs, oldest first
+    fn push(&mut self, sample: f32) -> &[f32] {
+        let length = self.buffer.len() / 2;
+        self.buffer[self.index] = sample;
+        self.buffer[self.index + length] = sample;
+        self.index = (self.index + 1) % length;
+        &self.buffer[self.index..self.index + length]
+    }
+}
+
+/// One 2x up and down sampling stage around a linear-phase half-band low-pass
+///
+/// Apart from the center tap every other tap of a half-band filter is zero, so each direction splits into one branch
+/// that is a plain delay and one short FIR branch running at the lower rate.
+struct HalfBandStage {
+    taps: Vec<f32>,  // The even taps of the half-band filter, time-reversed to line up with the oldest-first history
+    center: usize,  // Delay of the plain delay branch, in samples at the lower rate
+    up_history: History,
+    down_even_history: History,
+    down_odd_history: History,
+    dot_product: DotProduct,
+}
+
+impl HalfBandStage {
+    /// Designs a Kaiser-windowed half-band filter, ```num_taps``` has to be of the form 4m + 3
+    fn new(num_taps: usize) -> Self {
+        assert_eq!(num_taps % 4, 3);
+        let delay = (num_taps - 1) / 2;
+        let taps: Vec<f32> = (0..num_taps)
+            .step_by(2)
+            .rev()
+            .map(|i| {
+                let n = i as f64 - delay as f64;
+                let sinc = f64::sin(std::f64::consts::PI * n / 2.0) / (std::f64::consts::PI * n);
+                (sinc * Window::Kaiser(KAISER_BETA).value(i, num_taps)) as f32
+            })
+            .collect();
+        let branch_length = taps.len();
+
+        HalfBandStage {
+            taps,
+            center: delay / 2,
+            up_history: History::new(branch_length),
+            down_even_history: History::new(branch_length),
+            down_odd_history: History::new(branch_length),
+            dot_product: select_dot_product(),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.up_history.reset();
+        self.down_even_history.reset();
+        self.down_odd_history.reset();
+    }
+
+    /// Delay of an up and down sampling round trip, in samples at the higher rate
+    fn round_trip_delay(&self) -> usize {
+        2 * (2 * self.center + 1)
+    }
+
+    /// Return the two output samples at the higher rate for one input sample
+    fn upsample(&mut self, sample: f32) -> (f32, f32) {
+        let window = self.up_history.push(sample);
+        let last = window.len() - 1;
+        // Zero stuffing halves the level, which the gain of 2 makes up for, so the center tap of 0.5 becomes a plain copy
+        (2.0 * (self.dot_product)(window, &self.taps), window[last - self.center])
+    }
+
+    /// Return one output sample at the lower rate for two input samples
+    fn downsample(&mut self, even: f32, odd: f32) -> f32 {
+        let odd_window = self.down_odd_history.push(odd);
+        let delayed_odd = odd_window[odd_window.len() - 2 - self.center];
+        let even_window = self.down_even_history.push(even);
+        (self.dot_product)(even_window, &self.taps) + 0.5 * delayed_odd
+    }
+}
+
+/// Polyphase half-band oversampling by 2x, 4x or 8x
+///
+/// Each factor of two is its own stage, so the later stages at the higher rates can use much shorter filters.
+/// Blocks are oversampled in place: ```upsample``` expands the start of a buffer to fill it and ```downsample```
+/// shrinks it back.
+pub struct Oversampler {
+    stages: Vec<HalfBandStage>,
+    scratch: Vec<f32>,  // Copy of the block before each upsampling stage
+}
+
+impl Oversampler {
+    /// Creates an oversampler for blocks of up to ```max_block_size``` samples at the original rate
+    pub fn new(max_block_size: usize) -> Self {
+        let stages = (0..MAX_STAGES)
+            .map(|stage| HalfBandStage::new(if stage == 0 { FIRST_STAGE_TAPS } else { LATER_STAGE_TAPS }))
+            .collect();
+        Oversampler {
+            stages,
+            scratch: vec![0.0; max_block_size << (MAX_STAGES - 1)],
+        }
+    }
+
+    /// Clears the history of every stage
+    pub fn reset(&mut self) {
+        for stage in self.stages.iter_mut() {
+            stage.reset();
+        }
+    }
+
+    /// Delay of an up and down sampling round trip through ```num_stages``` stages, in samples at the original rate
+    pub fn latency(&self, num_stages: usize) -> f32 {
+        self.stages[..num_stages]
+            .iter()
+            .enumerate()
+            .map(|(stage, half_band)| half_band.round_trip_delay() as f32 / (2 << stage) as f32)
+            .sum()
+    }
+
+    /// Upsamples the first ```block.len() >> num_stages``` samples of ```block``` to fill all of it
+    pub fn upsample(&mut self, block: &mut [f32], num_stages: usize) {
+        let mut length = block.len() >> num_stages;
+        for stage in self.stages[..num_stages].iter_mut() {
+            self.scratch[..length].copy_from_slice(&block[..length]);
+            for (i, &sample) in self.scratch[..length].iter().enumerate() {
+                (block[2 * i], block[2 * i + 1]) = stage.upsample(sample);
+            }
+            length *= 2;
+        }
+    }
+
+    /// Downsamples all of ```block``` into its first ```block.len() >> num_stages``` samples
+    pub fn downsample(&mut self, block: &mut [f32], num_stages: usize) {
+        let mut length = block.len();
+        for stage in self.stages[..num_stages].iter_mut().rev() {
+            length /= 2;
+            // Sample i only depends on samples 2i and 2i + 1, so this can run in place front to back
+            for i in 0..length {
+                block[i] = stage.downsample(block[2 * i], block[2 * i + 1]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(freq: f32, sample_rate: f32, start: f32, length: usize) -> Vec<f32> {
+        (0..length)
+            .map(|i| f32::sin((i as f32 - start) * 2.0 * std::f32::consts::PI * freq / sample_rate))
+            .collect()
+    }
+
+    #[test]
+    fn test_round_trip_is_delayed_input() {
+        for num_stages in 0..=MAX_STAGES {
+            let mut oversampler = Oversampler::new(256);
+            let input = sine(1000.0, 44100.0, 0.0, 4096);
+            let mut output = Vec::new();
+            let mut block = vec![0.0; 256 << num_stages];
+            for chunk in input.chunks(256) {
+                block[..chunk.len()].copy_from_slice(chunk);
+                oversampler.upsample(&mut block, num_stages);
+                oversampler.downsample(&mut block, num_stages);
+                output.extend_from_slice(&block[..chunk.len()]);
+            }
+
+            // The fractional latencies of the 4x and 8x stages are exact as well
+            let expected = sine(1000.0, 44100.0, oversampler.latency(num_stages), 4096);
+            for i in 1000..4096 {
+                assert!(f32::abs(output[i] - expected[i]) < 0.001);
+            }
+        }
+    }
+
+    #[test]
+    fn test_latency() {
+        let oversampler = Oversampler::new(1);
+        assert_eq!(oversampler.latency(0), 0.0);
+        assert_eq!(oversampler.latency(1), (FIRST_STAGE_TAPS - 1) as f32 / 2.0);
+        assert_eq!(oversampler.latency(2), (FIRST_STAGE_TAPS - 1) as f32 / 2.0 + (LATER_STAGE_TAPS - 1) as f32 / 4.0);
+    }
+
+    #[test]
+    fn test_rejects_images() {
+        // A 10 kHz tone upsampled to 88.2 kHz must not leave an image mirrored around the old Nyquist frequency
+        let mut oversampler = Oversampler::new(4096);
+        let mut block = vec![0.0; 8192];
+        block[..4096].copy_from_slice(&sine(10000.0, 44100.0, 0.0, 4096));
+        oversampler.upsample(&mut block, 1);
+
+        let level = |freq: f32| {
+            let (re, im) = block[1000..].iter().enumerate().fold((0.0, 0.0), |(re, im), (i, x)| {
+                let phase = i as f32 * 2.0 * std::f32::consts::PI * freq / 88200.0;
+                (re + x * f32::cos(phase), im + x * f32::sin(phase))
+            });
+            2.0 * f32::sqrt(re * re + im * im) / (block.len() - 1000) as f32
+        };
+        assert!(f32::abs(level(10000.0) - 1.0) < 0.01);
+        assert!(level(34100.0) < 0.001);
+    }
+}
@@ -0,0 +1,108 @@
+/// Signature shared by every dot product implementation
+pub type DotProduct = fn(&[f32], &[f32]) -> f32;
+
+/// Return the fastest dot product the current CPU supports
+/// The CPU is only inspected here, so pick the implementation once and keep it instead of calling this per sample
+pub fn select_dot_product() -> DotProduct {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx") && is_x86_feature_detected!("fma") {
+            return dot_product_avx;
+        }
+        dot_product_scalar
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        dot_product_neon
+    }
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        dot_product_scalar
+    }
+}
+
+/// Plain dot product of the overlapping part of ```a``` and ```b```, used when no vector instructions are available
+pub fn dot_product_scalar(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b.iter()).map(|(x, y)| x * y).sum()
+}
+
+/// Dot product using 256 bit AVX registers and fused multiply-adds, 16 products per iteration
+/// Only returned by ```select_dot_product``` once both features have been detected
+#[cfg(target_arch = "x86_64")]
+fn dot_product_avx(a: &[f32], b: &[f32]) -> f32 {
+    // Safe because this function is only handed out when the CPU supports AVX and FMA
+    unsafe { dot_product_avx_impl(a, b) }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx,fma")]
+unsafe fn dot_product_avx_impl(a: &[f32], b: &[f32]) -> f32 {
+    use std::arch::x86_64::*;
+
+    let length = a.len().min(b.len());
+    let vector_length = length - length % 16;
+    // Two accumulators hide the latency of the fused multiply-adds
+    let mut sum_low = _mm256_setzero_ps();
+    let mut sum_high = _mm256_setzero_ps();
+    let mut i = 0;
+    while i < vector_length {
+        sum_low = _mm256_fmadd_ps(_mm256_loadu_ps(a.as_ptr().add(i)), _mm256_loadu_ps(b.as_ptr().add(i)), sum_low);
+        sum_high = _mm256_fmadd_ps(_mm256_loadu_ps(a.as_ptr().add(i + 8)), _mm256_loadu_ps(b.as_ptr().add(i + 8)), sum_high);
+        i += 16;
+    }
+
+    // Horizontal sum of the eight lanes
+    let sum = _mm256_add_ps(sum_low, sum_high);
+    let sum = _mm_add_ps(_mm256_castps256_ps128(sum), _mm256_extractf128_ps(sum, 1));
+    let sum = _mm_add_ps(sum, _mm_movehl_ps(sum, sum));
+    let sum = _mm_add_ss(sum, _mm_shuffle_ps(sum, sum, 1));
+    _mm_cvtss_f32(sum) + dot_product_scalar(&a[vector_length..length], &b[vector_length..length])
+}
+
+/// Dot product using 128 bit NEON registers, which every aarch64 CPU has, 8 products per iteration
+#[cfg(target_arch = "aarch64")]
+fn dot_product_neon(a: &[f32], b: &[f32]) -> f32 {
+    use std::arch::aarch64::*;
+
+    let length = a.len().min(b.len());
+    let vector_length = length - length % 8;
+    // Safe because NEON is part of the aarch64 baseline and every load stays within vector_length
+    unsafe {
+        let mut sum_low = vdupq_n_f32(0.0);
+        let mut sum_high = vdupq_n_f32(0.0);
+        let mut i = 0;
+        while i < vector_length {
+            sum_low = vfmaq_f32(sum_low, vld1q_f32(a.as_ptr().add(i)), vld1q_f32(b.as_ptr().add(i)));
+            sum_high = vfmaq_f32(sum_high, vld1q_f32(a.as_ptr().add(i + 4)), vld1q_f32(b.as_ptr().add(i + 4)));
+            i += 8;
+        }
+        vaddvq_f32(vaddq_f32(sum_low, sum_high)) + dot_product_scalar(&a[vector_length..length], &b[vector_length..length])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_scalar() {
+        let a: Vec<f32> = (0..300).map(|i| f32::sin(i as f32 * 0.37)).collect();
+        let b: Vec<f32> = (0..300).map(|i| f32::cos(i as f32 * 0.11)).collect();
+        let dot_product = select_dot_product();
+        // Every remainder length and misaligned start, like the windows the FIR filter passes in
+        for start in 0..17 {
+            for length in 0..(300 - start) {
+                let expected = dot_product_scalar(&a[start..start + length], &b[..length]);
+                let actual = dot_product(&a[start..start + length], &b[..length]);
+                assert!(f32::abs(expected - actual) < 0.0001 * (1.0 + length as f32));
+            }
+        }
+    }
+
+    #[test]
+    fn test_uses_shorter_slice() {
+        let dot_product = select_dot_product();
+        assert_eq!(dot_product(&[1.0; 40], &[2.0; 33]), 66.0);
+        assert_eq!(dot_product(&[], &[2.0; 3]), 0.0);
+    }
+}
@@ -0,0 +1,142 @@
+/// Lowest frequency the filter can be tuned to, keeps the coefficients away from zero
+const MIN_FREQ: f32 = 10.0;
+/// Highest frequency the filter can be tuned to, as a fraction of the sample rate
+const MAX_FREQ_RATIO: f32 = 0.49;
+const MIN_Q: f32 = 0.1;
+const MAX_Q: f32 = 50.0;
+
+/// Converts the lower and upper edges of a pass band, the way ```bandpass_fir``` is specified, to a center frequency and Q
+/// The center is the geometric mean of the edges and the Q follows from the bandwidth
+pub fn edges_to_center_q(f_low: f32, f_high: f32) -> (f32, f32) {
+    let f_low = f_low.max(MIN_FREQ);
+    let f_high = f_high.max(f_low + 1.0);
+    let center_freq = f32::sqrt(f_low * f_high);
+    (center_freq, center_freq / (f_high - f_low))
+}
+
+/// Converts a center frequency and Q back to the lower and upper edges of the pass band, the inverse of ```edges_to_center_q```
+pub fn center_q_to_edges(center_freq: f32, q: f32) -> (f32, f32) {
+    let center_freq = center_freq.max(MIN_FREQ);
+    let bandwidth = center_freq / q.clamp(MIN_Q, MAX_Q);
+    let f_low = f32::sqrt(center_freq * center_freq + bandwidth * bandwidth / 4.0) - bandwidth / 2.0;
+    (f_low, f_low + bandwidth)
+}
+
+/// Resonant band-pass state-variable filter
+///
+/// Uses the topology-preserving transform (TPT / zero-delay feedback) structure, which stays stable
+/// and free of zipper noise when the cutoff is changed every sample.
+/// The band-pass output is normalised so the gain at the center frequency is always 1.0
+pub struct StateVariableFilter {
+    ic1eq: f32,
+    ic2eq: f32,
+    g: f32,
+    k: f32,
+    sample_rate: f32,
+}
+
+impl StateVariableFilter {
+    /// Creates a new filter centered on 1 kHz with a Q of 1
+    pub fn new(sample_rate: f32) -> Self {
+        let mut svf = StateVariableFilter {
+            ic1eq: 0.0,
+            ic2eq: 0.0,
+            g: 0.0,
+            k: 1.0,
+            sample_rate,
+        };
+        svf.set_params(1000.0, 1.0);
+        svf
+    }
+
+    /// Clears the internal state without touching the tuning
+    pub fn reset(&mut self) {
+        self.ic1eq = 0.0;
+        self.ic2eq = 0.0;
+    }
+
+    /// Tunes the filter to ```center_freq``` in Hz with quality factor ```q```, can be called each sample
+    pub fn set_params(&mut self, center_freq: f32, q: f32) {
+        let center_freq = center_freq.clamp(MIN_FREQ, self.sample_rate * MAX_FREQ_RATIO);
+        let q = q.clamp(MIN_Q, MAX_Q);
+        self.g = f32::tan(std::f32::consts::PI * center_freq / self.sample_rate);
+        self.k = 1.0 / q;
+    }
+
+    /// Return the band-passed value of the current sample
+    pub fn process_one_sample(&mut self, sample: &f32) -> f32 {
+        let a1 = 1.0 / (1.0 + self.g * (self.g + self.k));
+        let a2 = self.g * a1;
+        let a3 = self.g * a2;
+
+        let v3 = *sample - self.ic2eq;
+        let v1 = a1 * self.ic1eq + a2 * v3;
+        let v2 = self.ic2eq + a2 * self.ic1eq + a3 * v3;
+        self.ic1eq = 2.0 * v1 - self.ic1eq;
+        self.ic2eq = 2.0 * v2 - self.ic2eq;
+
+        self.k * v1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine_gen(freq: f32, sample_rate: f32, length: usize) -> Vec<f32> {
+        (0..length)
+            .map(|i| f32::sin(i as f32 * 2.0 * std::f32::consts::PI * freq / sample_rate))
+            .collect()
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0.0, |acc, x| f32::max(acc, x.abs()))
+    }
+
+    #[test]
+    fn test_unity_gain_at_center() {
+        let mut svf = StateVariableFilter::new(44100.0);
+        svf.set_params(1000.0, 4.0);
+        let output: Vec<f32> = sine_gen(1000.0, 44100.0, 44100).iter().map(|x| svf.process_one_sample(x)).collect();
+        assert!(f32::abs(peak(&output[22050..]) - 1.0) < 0.01);
+    }
+
+    #[test]
+    fn test_rejects_outside_band() {
+        let mut svf = StateVariableFilter::new(44100.0);
+        let (center_freq, q) = edges_to_center_q(800.0, 1200.0);
+        svf.set_params(center_freq, q);
+        let output: Vec<f32> = sine_gen(8000.0, 44100.0, 44100).iter().map(|x| svf.process_one_sample(x)).collect();
+        assert!(peak(&output[22050..]) < 0.1);
+
+        svf.reset();
+        let output: Vec<f32> = vec![1.0; 44100].iter().map(|x| svf.process_one_sample(x)).collect();
+        assert!(peak(&output[22050..]) < 0.001);
+    }
+
+    #[test]
+    fn test_band_conversion_round_trip() {
+        let (center_freq, q) = edges_to_center_q(300.0, 1200.0);
+        assert!(f32::abs(center_freq - 600.0) < 0.001);
+        assert!(f32::abs(q - 600.0 / 900.0) < 0.001);
+
+        let (f_low, f_high) = center_q_to_edges(center_freq, q);
+        assert!(f32::abs(f_low - 300.0) < 0.01);
+        assert!(f32::abs(f_high - 1200.0) < 0.01);
+    }
+
+    #[test]
+    fn test_per_sample_sweep_is_stable() {
+        let mut svf = StateVariableFilter::new(44100.0);
+        let input = sine_gen(440.0, 44100.0, 44100);
+        for (i, x) in input.iter().enumerate() {
+            // Sweep well past both ends of the allowed range
+            let lfo = f32::sin(i as f32 * 2.0 * std::f32::consts::PI * 20.0 / 44100.0);
+            let (center_freq, q) = edges_to_center_q(-5000.0 + lfo * 30000.0, lfo * 40000.0);
+            svf.set_params(center_freq, q);
+            let output = svf.process_one_sample(x);
+            assert!(output.is_finite());
+            assert!(output.abs() < 10.0);
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use crate::svf::StateVariableFilter;
+
+/// Component-level character of a wah pedal circuit
+///
+/// Every value is given at the heel (pedal fully up, position 0.0) and toe (pedal fully down, position 1.0),
+/// positions in between are interpolated exponentially the way the pot taper moves the inductor resonance
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PedalVoicing {
+    /// Resonant frequency in Hz with the pedal at the heel
+    pub heel_freq: f32,
+    /// How many octaves the resonance travels from heel to toe
+    pub sweep_octaves: f32,
+    pub heel_q: f32,
+    pub toe_q: f32,
+    /// Linear gain at the resonant peak
+    pub heel_gain: f32,
+    pub toe_gain: f32,
+    /// Amount of unfiltered low end that leaks around the resonant network
+    pub bleed: f32,
+    /// Corner of the low-pass that shapes the leaked signal in Hz
+    pub bleed_freq: f32,
+    /// Level above which the transistor gain stage starts to round off the peak
+    pub headroom: f32,
+}
+
+/// Dunlop Cry Baby GCB-95: narrow, vocal resonance that is much louder at the heel
+pub const CRY_BABY: PedalVoicing = PedalVoicing {
+    heel_freq: 450.0,
+    sweep_octaves: 2.3,
+    heel_q: 8.0,
+    toe_q: 2.0,
+    heel_gain: 4.0,
+    toe_gain: 0.85,
+    bleed: 0.1,
+    bleed_freq: 150.0,
+    headroom: 2.0,
+};
+
+/// Vox V847: lower, broader sweep with a flatter level and more low end
+pub const VOX: PedalVoicing = PedalVoicing {
+    heel_freq: 400.0,
+    sweep_octaves: 2.2,
+    heel_q: 5.0,
+    toe_q: 2.5,
+    heel_gain: 2.5,
+    toe_gain: 0.9,
+    bleed: 0.25,
+    bleed_freq: 200.0,
+    headroom: 2.5,
+};
+
+/// Model of the classic inductor wah circuit
+///
+/// The resonant inductor/capacitor network is a band-pass whose frequency, Q and peak gain all follow the pedal position,
+/// plus a low-passed bleed of the input and a soft-clipping transistor stage.
+pub struct WahPedal {
+    voicing: PedalVoicing,
+    resonator: StateVariableFilter,
+    peak_gain: f32,
+    bleed_state: f32,
+    bleed_coeff: f32,
+    sample_rate: f32,
+}
+
+impl WahPedal {
+    /// Creates a new pedal resting at the heel
+    pub fn new(voicing: PedalVoicing, sample_rate: f32) -> Self {
+        let mut pedal = WahPedal {
+            voicing,
+            resonator: StateVariableFilter::new(sample_rate),
+            peak_gain: 1.0,
+            bleed_state: 0.0,
+            bleed_coeff: 0.0,
+            sample_rate,
+        };
+        pedal.set_voicing(voicing);
+        pedal.set_position(0.0);
+        pedal
+    }
+
+    /// Switches to a different circuit, keeps the filter state so the change does not click
+    /// The new tuning takes effect on the next ```set_position``` call
+    pub fn set_voicing(&mut self, voicing: PedalVoicing) {
+        self.voicing = voicing;
+        self.bleed_coeff = 1.0 - f32::exp(-2.0 * std::f32::consts::PI * voicing.bleed_freq / self.sample_rate);
+    }
+
+    /// Clears the internal state without touching the tuning
+    pub fn reset(&mut self) {
+        self.resonator.reset();
+        self.bleed_state = 0.0;
+    }
+
+    /// Moves the pedal, 0.0 is the heel and 1.0 the toe. Can be called each sample
+    pub fn set_position(&mut self, position: f32) {
+        let position = position.clamp(0.0, 1.0);
+        let voicing = &self.voicing;
+        let freq = voicing.heel_freq * f32::powf(2.0, voicing.sweep_octaves * position);
+        let q = voicing.heel_q * f32::powf(voicing.toe_q / voicing.heel_q, position);
+        self.peak_gain = voicing.heel_gain * f32::powf(voicing.toe_gain / voicing.heel_gain, position);
+        self.resonator.set_params(freq, q);
+    }
+
+    /// Moves the pedal to wherever its resonance sits closest to ```freq``` in Hz
+    pub fn set_center_freq(&mut self, freq: f32) {
+        let octaves = f32::log2(freq.max(1.0) / self.voicing.heel_freq);
+        self.set_position(octaves / self.voicing.sweep_octaves);
+    }
+
+    /// Return the output of the circuit for the current sample
+    pub fn process_one_sample(&mut self, sample: &f32) -> f32 {
+        self.bleed_state += self.bleed_coeff * (*sample - self.bleed_state);
+        let resonance = self.resonator.process_one_sample(sample) * self.peak_gain;
+        let headroom = self.voicing.headroom;
+        headroom * f32::tanh(resonance / headroom) + self.voicing.bleed * self.bleed_state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine_peak(pedal: &mut WahPedal, freq: f32, sample_rate: f32) -> f32 {
+        pedal.reset();
+        let length = sample_rate as usize;
+        let mut peak = 0.0;
+        for i in 0..length {
+            // Keep the level low so the transistor stage stays linear
+            let input = 0.01 * f32::sin(i as f32 * 2.0 * std::f32::consts::PI * freq / sample_rate);
+            let output = pedal.process_one_sample(&input);
+            if i > length / 2 {
+                peak = f32::max(peak, output.abs() / 0.01);
+            }
+        }
+        peak
+    }
+
+    #[test]
+    fn test_resonance_follows_position() {
+        let mut pedal = WahPedal::new(CRY_BABY, 44100.0);
+        pedal.set_position(0.0);
+        assert!(sine_peak(&mut pedal, 450.0, 44100.0) > sine_peak(&mut pedal, 1800.0, 44100.0));
+        pedal.set_position(1.0);
+        assert!(sine_peak(&mut pedal, 1800.0, 44100.0) > sine_peak(&mut pedal, 450.0, 44100.0));
+    }
+
+    #[test]
+    fn test_peak_gain_and_bleed() {
+        let mut pedal = WahPedal::new(CRY_BABY, 44100.0);
+        pedal.set_position(0.0);
+        let heel_peak = sine_peak(&mut pedal, 450.0, 44100.0);
+        assert!(f32::abs(heel_peak - CRY_BABY.heel_gain) < 0.2);
+
+        // Only the bleed path passes DC
+        pedal.reset();
+        let mut output = 0.0;
+        for _ in 0..44100 {
+            output = pedal.process_one_sample(&0.01);
+        }
+        assert!(f32::abs(output / 0.01 - CRY_BABY.bleed) < 0.01);
+    }
+
+    #[test]
+    fn test_center_freq_maps_to_position() {
+        let mut pedal = WahPedal::new(VOX, 44100.0);
+        pedal.set_center_freq(VOX.heel_freq * f32::powf(2.0, VOX.sweep_octaves));
+        let mut reference = WahPedal::new(VOX, 44100.0);
+        reference.set_position(1.0);
+        assert!(f32::abs(sine_peak(&mut pedal, 1500.0, 44100.0) - sine_peak(&mut reference, 1500.0, 44100.0)) < 0.0001);
+    }
+
+    #[test]
+    fn test_output_stays_bounded() {
+        let mut pedal = WahPedal::new(CRY_BABY, 44100.0);
+        for i in 0..44100 {
+            pedal.set_center_freq(-1000.0 + (i % 100) as f32 * 500.0);
+            let output = pedal.process_one_sample(&1.0);
+            assert!(output.is_finite());
+            assert!(output.abs() <= CRY_BABY.headroom + CRY_BABY.bleed);
+        }
+    }
+}